@@ -0,0 +1,416 @@
+use std::collections::{hash_map::Entry, HashMap};
+
+use crate::{
+    diagnostic::{Diagnostic, Severity},
+    instructions::{Instruction, Value},
+    program::{FunctionEnd, Program},
+};
+
+/// Checks a program's labels, functions and control flow before it is run or
+/// compiled, returning every problem found in program order.
+pub fn check(program: &Program) -> Vec<Diagnostic> {
+    let mut checker = Checker {
+        program,
+        found: Vec::new(),
+    };
+
+    let ends = program.function_ends();
+    let (labels, functions) = checker.check_declarations();
+    checker.check_targets(&labels, &functions);
+    checker.check_functions(&ends);
+    checker.check_reachability();
+
+    checker.found.sort_by_key(|(index, _)| *index);
+    checker
+        .found
+        .into_iter()
+        .map(|(_, diagnostic)| diagnostic)
+        .collect()
+}
+
+#[derive(PartialEq)]
+enum Reachability {
+    Reachable,
+    Unreachable,
+    /// Unreachable, and already warned about.
+    Reported,
+}
+
+struct Checker<'a> {
+    program: &'a Program,
+    found: Vec<(usize, Diagnostic)>,
+}
+
+impl Checker<'_> {
+    fn check_declarations(&mut self) -> (HashMap<u16, usize>, HashMap<u16, usize>) {
+        let mut labels: HashMap<u16, usize> = HashMap::new();
+        let mut functions: HashMap<u16, usize> = HashMap::new();
+
+        for (i, instruction) in self.program.instructions.iter().enumerate() {
+            let (declared, kind, id) = match instruction {
+                Instruction::Label(Value::Literal(id)) => (&mut labels, "Label", *id),
+                Instruction::Function(Value::Literal(id)) => (&mut functions, "Function", *id),
+                Instruction::Label(_) | Instruction::Function(_) => {
+                    self.report(
+                        Severity::Error,
+                        i,
+                        "Labels and functions must be declared with a literal id".to_string(),
+                        None,
+                    );
+                    continue;
+                }
+                _ => continue,
+            };
+
+            match declared.entry(id) {
+                Entry::Occupied(first) => {
+                    let first = *first.get();
+                    self.report(
+                        Severity::Error,
+                        i,
+                        format!("{} {} is declared more than once", kind, self.name(id)),
+                        Some(("First declared here", first)),
+                    );
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(i);
+                }
+            }
+        }
+
+        for (id, label) in &labels {
+            if let Some(function) = functions.get(id) {
+                self.report(
+                    Severity::Error,
+                    *label,
+                    format!("Label {} has the same id as a function", self.name(*id)),
+                    Some(("Function declared here", *function)),
+                );
+            }
+        }
+
+        (labels, functions)
+    }
+
+    fn check_targets(&mut self, labels: &HashMap<u16, usize>, functions: &HashMap<u16, usize>) {
+        for (i, instruction) in self.program.instructions.iter().enumerate() {
+            match instruction {
+                Instruction::BranchIfEqual(Value::Literal(id))
+                | Instruction::BranchIfNotEqual(Value::Literal(id))
+                | Instruction::BranchIfCarrySet(Value::Literal(id))
+                | Instruction::BranchIfCarryClear(Value::Literal(id))
+                | Instruction::BranchIfOverflowSet(Value::Literal(id))
+                | Instruction::BranchIfOverflowClear(Value::Literal(id))
+                | Instruction::BranchIfZeroSet(Value::Literal(id))
+                | Instruction::BranchIfZeroClear(Value::Literal(id))
+                | Instruction::BranchIfNegative(Value::Literal(id))
+                | Instruction::BranchIfPositive(Value::Literal(id))
+                    if !labels.contains_key(id) =>
+                {
+                    self.report(
+                        Severity::Error,
+                        i,
+                        format!("Use of undeclared label {}", self.name(*id)),
+                        None,
+                    );
+                }
+                Instruction::Jump(Value::Literal(id)) if !labels.contains_key(id) => {
+                    match functions.get(id) {
+                        Some(function) => self.report(
+                            Severity::Warning,
+                            i,
+                            format!(
+                                "JMP no longer calls functions, use CAL to call function {}",
+                                self.name(*id)
+                            ),
+                            Some(("Function declared here", *function)),
+                        ),
+                        None => self.report(
+                            Severity::Error,
+                            i,
+                            format!("Use of undeclared label {}", self.name(*id)),
+                            None,
+                        ),
+                    }
+                }
+                Instruction::Call(Value::Literal(id)) if !functions.contains_key(id) => {
+                    self.report(
+                        Severity::Error,
+                        i,
+                        format!("Use of undeclared function {}", self.name(*id)),
+                        None,
+                    );
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn check_functions(&mut self, ends: &HashMap<usize, FunctionEnd>) {
+        // PRM moves the frame, so only makes sense before anything uses it
+        let instructions = &self.program.instructions;
+        for (i, instruction) in instructions.iter().enumerate() {
+            if matches!(instruction, Instruction::Parameters(_))
+                && !matches!(
+                    i.checked_sub(1).map(|i| &instructions[i]),
+                    Some(Instruction::Function(_))
+                )
+            {
+                self.report(
+                    Severity::Error,
+                    i,
+                    "PRM must come straight after FUN".to_string(),
+                    None,
+                );
+            }
+        }
+
+        let mut starts: Vec<&usize> = ends.keys().collect();
+        starts.sort();
+
+        for start in starts {
+            match ends[start] {
+                FunctionEnd::Marker(_) => {}
+                FunctionEnd::Nested(nested) => self.report(
+                    Severity::Error,
+                    nested,
+                    "Function declared inside another function".to_string(),
+                    Some(("Enclosing function declared here", *start)),
+                ),
+                FunctionEnd::Missing => self.report(
+                    Severity::Error,
+                    *start,
+                    "Function has no matching EFN".to_string(),
+                    None,
+                ),
+            }
+        }
+    }
+
+    /// Code after an `EXT`, `JMP` or `RTN` can only be reached through a
+    /// following `LAB` or `FUN`, or the end of the function it is in. A `RTN`
+    /// outside of a function is most likely left over from when `JMP` could
+    /// call.
+    fn check_reachability(&mut self) {
+        let mut state = Reachability::Reachable;
+        let mut in_function = false;
+
+        for (i, instruction) in self.program.instructions.iter().enumerate() {
+            if matches!(
+                instruction,
+                Instruction::Label(_) | Instruction::Function(_) | Instruction::EndFunction
+            ) {
+                state = Reachability::Reachable;
+            } else if state == Reachability::Unreachable {
+                self.report(Severity::Warning, i, "Unreachable code".to_string(), None);
+                state = Reachability::Reported;
+            }
+
+            match instruction {
+                Instruction::Function(_) => in_function = true,
+                Instruction::EndFunction if in_function => in_function = false,
+                Instruction::EndFunction => self.report(
+                    Severity::Error,
+                    i,
+                    "EFN outside of a function".to_string(),
+                    None,
+                ),
+                Instruction::Return if !in_function => {
+                    self.report(
+                        Severity::Warning,
+                        i,
+                        "RTN outside of a function, JMP no longer records a return address"
+                            .to_string(),
+                        None,
+                    );
+
+                    if state == Reachability::Reachable {
+                        state = Reachability::Unreachable;
+                    }
+                }
+                Instruction::Return
+                | Instruction::ReturnValue(_)
+                | Instruction::Exit(_)
+                | Instruction::Jump(_)
+                    if state == Reachability::Reachable =>
+                {
+                    state = Reachability::Unreachable;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Refers to a label or function by its name in the source, or by its id
+    /// if it doesn't have one.
+    fn name(&self, id: u16) -> String {
+        match self.program.names.get(&id) {
+            Some(name) => name.clone(),
+            None => format!("{:#06x}", id),
+        }
+    }
+
+    fn report(
+        &mut self,
+        severity: Severity,
+        index: usize,
+        message: String,
+        note: Option<(&str, usize)>,
+    ) {
+        let spans = &self.program.spans;
+
+        // Compiled programs have no spans, so point at instructions by index
+        let message = match (spans.get(index), note) {
+            (Some(_), _) => message,
+            (None, Some((_, other))) => {
+                format!("{} (instructions {} and {})", message, index, other)
+            }
+            (None, None) => format!("{} (instruction {})", message, index),
+        };
+
+        let notes = note
+            .and_then(|(message, other)| Some((message.to_string(), spans.get(other)?.clone())))
+            .into_iter()
+            .collect();
+
+        self.found.push((
+            index,
+            Diagnostic {
+                severity,
+                message,
+                span: spans.get(index).cloned(),
+                notes,
+            },
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{diagnostic::SourceFile, lexer::lex_source};
+
+    fn messages(text: &str) -> Vec<String> {
+        let program = lex_source(SourceFile {
+            path: "test.jasm".to_string(),
+            text: text.to_string(),
+        })
+        .unwrap();
+
+        check(&program)
+            .iter()
+            .map(|diagnostic| diagnostic.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn valid_program() {
+        assert!(messages("FUN print\nCUT\nEFN\nLAB loop\nCAL print\nBNE loop\n").is_empty());
+    }
+
+    #[test]
+    fn undeclared_targets() {
+        let messages = messages("LAB 0x01\nBEQ 0x02\nCAL 0x01\nJMP 0x03\nLAB 0x04\nJMP 0x01\n");
+        assert_eq!(messages.len(), 3);
+        assert!(
+            messages[0].starts_with("error: Use of undeclared label 0x0002\n --> test.jasm:2:1")
+        );
+        assert!(
+            messages[1].starts_with("error: Use of undeclared function 0x0001\n --> test.jasm:3:1")
+        );
+        assert!(
+            messages[2].starts_with("error: Use of undeclared label 0x0003\n --> test.jasm:4:1")
+        );
+    }
+
+    #[test]
+    fn undeclared_names() {
+        let messages = messages("CAL bar\nMAC M\nLAB top\nLAB top\nEND\nM\nJMP foo\n");
+        assert_eq!(messages.len(), 3);
+        assert!(
+            messages[0].starts_with("error: Use of undeclared function bar\n --> test.jasm:1:1")
+        );
+        assert!(messages[1].starts_with("error: Label top is declared more than once\n"));
+        assert!(messages[2].starts_with("error: Use of undeclared label foo\n --> test.jasm:7:1"));
+    }
+
+    #[test]
+    fn jump_migration() {
+        let messages = messages("FUN 0x01\nEFN\nJMP 0x01\nOUT\nLAB 0x02\nRTN\n");
+        assert_eq!(messages.len(), 3);
+        assert!(messages[0].starts_with(
+            "warning: JMP no longer calls functions, use CAL to call function 0x0001\n --> test.jasm:3:1"
+        ));
+        assert!(messages[0].contains("note: Function declared here\n --> test.jasm:1:1"));
+        assert!(messages[1].starts_with("warning: Unreachable code\n --> test.jasm:4:1"));
+        assert!(messages[2].starts_with(
+            "warning: RTN outside of a function, JMP no longer records a return address\n --> test.jasm:6:1"
+        ));
+    }
+
+    #[test]
+    fn parameters() {
+        assert!(messages("FUN f\nPRM 1\nEFN\n").is_empty());
+
+        let messages = messages("PRM 1\nFUN f\nLOC 1\nPRM 1\nEFN\n");
+        assert_eq!(messages.len(), 2);
+        assert!(
+            messages[0].starts_with("error: PRM must come straight after FUN\n --> test.jasm:1:1")
+        );
+        assert!(
+            messages[1].starts_with("error: PRM must come straight after FUN\n --> test.jasm:4:1")
+        );
+    }
+
+    #[test]
+    fn duplicates_and_collisions() {
+        let messages = messages("LAB loop\nLAB loop\nFUN 0x01\nEFN\nLAB 0x01\n");
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].starts_with("error: Label loop is declared more than once"));
+        assert!(messages[0].contains("note: First declared here\n --> test.jasm:1:1"));
+        assert!(messages[1].starts_with("error: Label 0x0001 has the same id as a function"));
+        assert!(messages[1].contains("note: Function declared here\n --> test.jasm:3:1"));
+    }
+
+    #[test]
+    fn unbalanced_functions() {
+        let messages = messages("FUN 0x01\nFUN 0x02\nEFN\nEFN\nFUN 0x03\nOUT\n");
+        assert_eq!(messages.len(), 3);
+        assert!(messages[0].starts_with("error: Function declared inside another function"));
+        assert!(messages[0].contains("note: Enclosing function declared here\n --> test.jasm:1:1"));
+        assert!(messages[1].starts_with("error: EFN outside of a function\n --> test.jasm:4:1"));
+        assert!(messages[2].starts_with("error: Function has no matching EFN\n --> test.jasm:5:1"));
+    }
+
+    #[test]
+    fn functions_without_efn() {
+        // An early RTN doesn't end the body, so the code after it is still
+        // part of the function
+        let messages = messages("FUN 0x01\nBEQ 0x02\nRTN\nLAB 0x02\nRTN\n");
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].starts_with("error: Function has no matching EFN\n --> test.jasm:1:1"));
+    }
+
+    #[test]
+    fn unreachable_code() {
+        let messages =
+            messages("EXT 0x00\nOUT\nOUT\nLAB 0x01\nJMP 0x01\nOUT\nFUN 0x02\nEXT 0x01\nRTN\nEFN\n");
+        assert_eq!(messages.len(), 3);
+        assert!(messages[0].starts_with("warning: Unreachable code\n --> test.jasm:2:1"));
+        assert!(messages[1].starts_with("warning: Unreachable code\n --> test.jasm:6:1"));
+        assert!(messages[2].starts_with("warning: Unreachable code\n --> test.jasm:9:1"));
+    }
+
+    #[test]
+    fn compiled_programs_use_indices() {
+        let program = Program {
+            instructions: vec![Instruction::Jump(Value::Literal(0x01))],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            check(&program)[0].to_string(),
+            "error: Use of undeclared label 0x0001 (instruction 0)"
+        );
+    }
+}
@@ -1,21 +1,37 @@
-use anyhow::Result;
+use crate::{
+    instructions::{Instruction, Value},
+    program::Program,
+};
 
-use crate::instructions::{Instruction, Value};
+/// Marks a data record, which takes the place of an instruction's addressing
+/// mode byte. It is followed by a padding byte, the address, the number of
+/// words and then the words themselves.
+pub const DATA_RECORD: u8 = 0xDA;
 
-pub fn compile(instructions: Vec<Instruction>) -> Result<Vec<u8>> {
+pub fn compile(program: Program) -> Vec<u8> {
     let mut compiled: Vec<u8> = Vec::new();
 
-    for instruction in instructions {
+    for block in &program.data {
+        compiled.push(DATA_RECORD);
+        compiled.push(0x00);
+        compiled.extend(block.address.to_be_bytes());
+        compiled.extend((block.words.len() as u16).to_be_bytes());
+        for word in &block.words {
+            compiled.extend(word.to_be_bytes());
+        }
+    }
+
+    for instruction in program.instructions {
         if instruction.requires_value() {
             push_instruction_with_value(&mut compiled, &instruction);
             push_value(&mut compiled, &instruction.get_value());
         } else {
             compiled.push(0x00);
-            compiled.push(instruction.to_u8()?);
+            compiled.push(instruction.to_u8());
         }
     }
 
-    Ok(compiled)
+    compiled
 }
 
 fn push_instruction_with_value(compiled: &mut Vec<u8>, instruction: &Instruction) {
@@ -24,18 +40,19 @@ fn push_instruction_with_value(compiled: &mut Vec<u8>, instruction: &Instruction
     compiled.push(match value {
         Value::Literal(_) => 0x00,
         Value::Address(_) => 0x10,
+        Value::Frame(_) => 0x20,
+        Value::FrameAddress(_) => 0x30,
     });
 
-    compiled.push(instruction.to_u8().unwrap());
+    compiled.push(instruction.to_u8());
 }
 
 fn push_value(compiled: &mut Vec<u8>, value: &Value) {
     match value {
-        Value::Literal(val) => {
-            compiled.push((val >> 8) as u8);
-            compiled.push(*val as u8);
-        }
-        Value::Address(val) => {
+        Value::Literal(val)
+        | Value::Address(val)
+        | Value::Frame(val)
+        | Value::FrameAddress(val) => {
             compiled.push((val >> 8) as u8);
             compiled.push(*val as u8);
         }
@@ -0,0 +1,144 @@
+use std::{error::Error, fmt, sync::Arc};
+
+pub struct SourceFile {
+    pub path: String,
+    pub text: String,
+}
+
+impl fmt::Debug for SourceFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SourceFile({})", self.path)
+    }
+}
+
+/// A range of characters on a single line of a source file. Lines and columns
+/// are 1-based.
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub file: Arc<SourceFile>,
+    pub line: usize,
+    pub column: usize,
+    pub length: usize,
+}
+
+impl Span {
+    /// Returns a span covering both `self` and `other`. Tokens substituted
+    /// for a macro parameter come from the call instead of the macro body, so
+    /// if `other` is on a different line it is returned by itself.
+    pub fn to(&self, other: &Span) -> Span {
+        if !Arc::ptr_eq(&self.file, &other.file) || self.line != other.line {
+            return other.clone();
+        }
+
+        Span {
+            file: self.file.clone(),
+            line: self.line,
+            column: self.column,
+            length: other.column + other.length - self.column,
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file.path, self.line, self.column)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// Where the problem is, if the program came from source rather than a
+    /// compiled file.
+    pub span: Option<Span>,
+    /// Secondary locations, such as the macro invocation an error came from.
+    pub notes: Vec<(String, Span)>,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>, span: &Span) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.into(),
+            span: Some(span.clone()),
+            notes: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            Severity::Error => write!(f, "error: {}", self.message)?,
+            Severity::Warning => write!(f, "warning: {}", self.message)?,
+        }
+
+        if let Some(span) = &self.span {
+            writeln!(f)?;
+            write_snippet(f, span)?;
+        }
+
+        for (message, span) in &self.notes {
+            writeln!(f)?;
+            writeln!(f, "note: {}", message)?;
+            write_snippet(f, span)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn write_snippet(f: &mut fmt::Formatter<'_>, span: &Span) -> fmt::Result {
+    let text = span.file.text.lines().nth(span.line - 1).unwrap_or("");
+    let line_number = span.line.to_string();
+    let gutter = " ".repeat(line_number.len());
+
+    // Keep tabs so the carets line up with the source line
+    let indent: String = text
+        .chars()
+        .take(span.column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    writeln!(f, "{}--> {}", gutter, span)?;
+    writeln!(f, "{} |", gutter)?;
+    writeln!(f, "{} | {}", line_number, text)?;
+    write!(
+        f,
+        "{} | {}{}",
+        gutter,
+        indent,
+        "^".repeat(span.length.max(1))
+    )
+}
+
+impl Error for Diagnostic {}
+
+/// Every diagnostic produced while processing a file, so that all of them can
+/// be reported at once.
+#[derive(Debug)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, diagnostic) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+                writeln!(f)?;
+            }
+
+            write!(f, "{}", diagnostic)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Error for Diagnostics {}
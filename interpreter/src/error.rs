@@ -0,0 +1,265 @@
+use std::{error, fmt, io, num::ParseIntError, time::Duration};
+
+use crate::{
+    diagnostic::{Diagnostic, Diagnostics, Severity, Span},
+    instructions::InstructionError,
+    MAX_MEMORY_SIZE, MIN_MEMORY_SIZE,
+};
+
+/// Everything that can go wrong while loading, checking or running a program.
+#[derive(Debug)]
+pub enum Error {
+    /// A `.jasm` file could not be lexed or parsed.
+    Parse(Diagnostics),
+    /// A program failed the checks run before it is compiled or run.
+    Check(Diagnostics),
+    /// A `.jasmb` file could not be decoded.
+    Decode(DecodeError),
+    /// A program faulted while running.
+    Runtime(RuntimeError),
+    /// A memory size outside of `MIN_MEMORY_SIZE..=MAX_MEMORY_SIZE` was asked
+    /// for.
+    InvalidMemorySize(usize),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse(diagnostics) | Error::Check(diagnostics) => write!(f, "{}", diagnostics),
+            Error::Decode(err) => write!(f, "error: {}", err),
+            Error::Runtime(err) => write!(f, "{}", err),
+            Error::InvalidMemorySize(size) => write!(
+                f,
+                "error: Memory size must be between {} and {} words, not {}",
+                MIN_MEMORY_SIZE, MAX_MEMORY_SIZE, size
+            ),
+            Error::Io(err) => write!(f, "error: {}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Parse(diagnostics) | Error::Check(diagnostics) => Some(diagnostics),
+            Error::Decode(err) => Some(err),
+            Error::Runtime(err) => Some(err),
+            Error::InvalidMemorySize(_) => None,
+            Error::Io(err) => Some(err),
+        }
+    }
+}
+
+impl From<Diagnostics> for Error {
+    fn from(diagnostics: Diagnostics) -> Self {
+        Error::Parse(diagnostics)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(err: DecodeError) -> Self {
+        Error::Decode(err)
+    }
+}
+
+impl From<RuntimeError> for Error {
+    fn from(err: RuntimeError) -> Self {
+        Error::Runtime(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// A malformed `.jasmb` file. Each variant holds the byte offset of the
+/// problem.
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    UnexpectedEnd(usize),
+    DataOutOfMemory(usize),
+    InvalidInstruction(usize, InstructionError),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd(offset) => {
+                write!(f, "Unexpected end of file at byte {}", offset)
+            }
+            DecodeError::DataOutOfMemory(offset) => {
+                write!(f, "Data at byte {} does not fit in memory", offset)
+            }
+            DecodeError::InvalidInstruction(offset, err) => {
+                write!(f, "Invalid instruction at byte {}: {}", offset, err)
+            }
+        }
+    }
+}
+
+impl error::Error for DecodeError {}
+
+/// A malformed number, character or string literal. Each variant holds the
+/// literal as written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LiteralError {
+    UnknownNumber(String),
+    InvalidNumber(String, ParseIntError),
+    NumberOutOfRange(String),
+    InvalidCharacter(String),
+    EmptyCharacter(String),
+    MultipleCharacters(String),
+    WideCharacter(String),
+    InvalidString(String),
+    UnknownEscape(String),
+}
+
+impl LiteralError {
+    pub fn diagnostic(&self, span: &Span) -> Diagnostic {
+        Diagnostic::new(self.to_string(), span)
+    }
+}
+
+impl fmt::Display for LiteralError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LiteralError::UnknownNumber(token) => write!(f, "Unknown number literal {}", token),
+            LiteralError::InvalidNumber(token, err) => {
+                write!(f, "Invalid number literal {}: {}", token, err)
+            }
+            LiteralError::NumberOutOfRange(token) => {
+                write!(f, "Number literal {} is out of range", token)
+            }
+            LiteralError::InvalidCharacter(token) => {
+                write!(f, "Invalid character literal {}", token)
+            }
+            LiteralError::EmptyCharacter(token) => write!(f, "Empty character literal {}", token),
+            LiteralError::MultipleCharacters(token) => write!(
+                f,
+                "Character literal {} contains more than one character",
+                token
+            ),
+            LiteralError::WideCharacter(token) => {
+                write!(f, "Character literal {} does not fit in 16 bits", token)
+            }
+            LiteralError::InvalidString(token) => write!(f, "Invalid string literal {}", token),
+            LiteralError::UnknownEscape(token) => {
+                write!(f, "Unknown escape sequence in {}", token)
+            }
+        }
+    }
+}
+
+impl error::Error for LiteralError {}
+
+/// Why a running program was stopped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    DivisionByZero,
+    StackOverflow,
+    StackUnderflow,
+    InvalidStackPointer,
+    MissingFunctionEnd,
+    NoCaller,
+    UndeclaredLabel(u16),
+    UndeclaredFunction(u16),
+    InvalidCharacter(u16),
+    /// A character read by `CIN` that is outside of the Basic Multilingual
+    /// Plane, so does not fit in a cell.
+    WideCharacter(char),
+    /// An address past the end of memory, once paged.
+    AddressOutOfRange(usize),
+    FrameSlotOutOfRange(u16),
+    /// A function took more arguments with `PRM` than its caller passed.
+    MissingArguments {
+        expected: u16,
+        passed: u16,
+    },
+    /// The program ran for as many instructions as it was allowed to.
+    StepLimit(u64),
+    /// The program ran for as long as it was allowed to.
+    Timeout(Duration),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::DivisionByZero => write!(f, "Division by zero"),
+            Fault::StackOverflow => write!(f, "Stack overflow"),
+            Fault::StackUnderflow => write!(f, "Stack underflow"),
+            Fault::InvalidStackPointer => write!(f, "Stack pointer is outside of the stack"),
+            Fault::MissingFunctionEnd => write!(f, "Function has no matching EFN"),
+            Fault::NoCaller => write!(f, "No caller to return to"),
+            Fault::UndeclaredLabel(id) => write!(f, "Use of undeclared label {:#06x}", id),
+            Fault::UndeclaredFunction(id) => write!(f, "Use of undeclared function {:#06x}", id),
+            Fault::InvalidCharacter(value) => write!(f, "Invalid character {:#06x}", value),
+            Fault::WideCharacter(char) => write!(
+                f,
+                "Character {} (U+{:04X}) does not fit in a cell",
+                char, *char as u32
+            ),
+            Fault::AddressOutOfRange(address) => {
+                write!(f, "Address {:#06x} is outside of memory", address)
+            }
+            Fault::FrameSlotOutOfRange(offset) => {
+                write!(f, "Frame slot @{} is outside of the stack", offset)
+            }
+            Fault::MissingArguments { expected, passed } => write!(
+                f,
+                "Function takes {} argument(s) but only {} were passed",
+                expected, passed
+            ),
+            Fault::StepLimit(steps) => write!(f, "Step limit of {} reached", steps),
+            Fault::Timeout(timeout) => write!(f, "Timed out after {:?}", timeout),
+        }
+    }
+}
+
+/// A fault, along with where the program was when it happened.
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub fault: Fault,
+    /// The index of the instruction that faulted.
+    pub index: usize,
+    /// The value of the address register when it faulted.
+    pub address: u16,
+    /// The index of each `CAL` that led to the fault, innermost first.
+    pub calls: Vec<usize>,
+    /// The source location of the instruction that faulted and of each call,
+    /// for programs that came from source.
+    pub spans: Option<(Span, Vec<Span>)>,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut message = format!("{} (ADDRESS is {:#06x})", self.fault, self.address);
+
+        let Some((span, call_spans)) = &self.spans else {
+            // Compiled programs have no spans, so point at instructions by index
+            message = format!("{} at instruction {}", message, self.index);
+
+            for call in &self.calls {
+                message = format!("{}, called from instruction {}", message, call);
+            }
+
+            return write!(f, "error: {}", message);
+        };
+
+        let diagnostic = Diagnostic {
+            severity: Severity::Error,
+            message,
+            span: Some(span.clone()),
+            notes: call_spans
+                .iter()
+                .map(|span| ("Called from here".to_string(), span.clone()))
+                .collect(),
+        };
+
+        write!(f, "{}", diagnostic)
+    }
+}
+
+impl error::Error for RuntimeError {}
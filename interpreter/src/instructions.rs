@@ -1,5 +1,14 @@
-use crate::Memory;
-use anyhow::{anyhow, Result};
+use crate::{error::Fault, Memory, CARRY_FLAG, NEGATIVE_FLAG, OVERFLOW_FLAG, ZERO_FLAG};
+use std::{error::Error, fmt};
+
+/// Instruction mnemonics, indexed by opcode.
+const MNEMONICS: [&str; 53] = [
+    "OUT", "CUT", "CIN", "DMP", "RTN", "SEA", "SET", "ADD", "SUB", "MUL", "DIV", "LAB", "CEQ",
+    "GTN", "LTN", "GTE", "LTE", "BNE", "BEQ", "JMP", "EXT", "FUN", "AND", "ORR", "XOR", "SHL",
+    "SHR", "NOT", "MOD", "SDV", "SMD", "SGT", "SLT", "SGE", "SLE", "BCS", "BCC", "BVS", "BVC",
+    "BZS", "BZC", "BMI", "BPL", "PSH", "POP", "PEK", "CAL", "EFN", "ARG", "LOC", "RTV", "PAG",
+    "PRM",
+];
 
 #[derive(Debug, Clone)]
 pub enum Instruction {
@@ -25,28 +34,92 @@ pub enum Instruction {
     Jump(Value),
     Exit(Value),
     Function(Value),
+    And(Value),
+    Or(Value),
+    Xor(Value),
+    ShiftLeft(Value),
+    ShiftRight(Value),
+    Not,
+    Modulo(Value),
+    SignedDivide(Value),
+    SignedModulo(Value),
+    SignedGreaterThan(Value),
+    SignedLessThan(Value),
+    SignedGreaterThanEqual(Value),
+    SignedLessThanEqual(Value),
+    BranchIfCarrySet(Value),
+    BranchIfCarryClear(Value),
+    BranchIfOverflowSet(Value),
+    BranchIfOverflowClear(Value),
+    BranchIfZeroSet(Value),
+    BranchIfZeroClear(Value),
+    BranchIfNegative(Value),
+    BranchIfPositive(Value),
+    Push(Value),
+    Pop,
+    Peek,
+    Call(Value),
+    EndFunction,
+    Argument(Value),
+    Locals(Value),
+    ReturnValue(Value),
+    Page(Value),
+    Parameters(Value),
 }
 
 impl Instruction {
     pub fn u8_requires_value(instruction: u8) -> bool {
-        match instruction {
-            0x00 => false,
-            0x01 => false,
-            0x02 => false,
-            0x03 => false,
-            0x04 => false,
-            _ => true,
-        }
+        matches!(instruction, 0x05..=0x1A | 0x1C..=0x2B | 0x2E | 0x30..=0x34)
     }
 
     pub fn requires_value(&self) -> bool {
+        !matches!(
+            self,
+            Self::Output
+                | Self::CharacterOutput
+                | Self::CharacterInput
+                | Self::Dump
+                | Self::Return
+                | Self::Not
+                | Self::Pop
+                | Self::Peek
+                | Self::EndFunction
+        )
+    }
+
+    pub fn takes_label(instruction: &str) -> bool {
+        matches!(
+            instruction,
+            "LAB"
+                | "FUN"
+                | "JMP"
+                | "CAL"
+                | "BEQ"
+                | "BNE"
+                | "BCS"
+                | "BCC"
+                | "BVS"
+                | "BVC"
+                | "BZS"
+                | "BZC"
+                | "BMI"
+                | "BPL"
+        )
+    }
+
+    /// The status flag a branch tests, and whether it branches when that flag
+    /// is set or when it is clear.
+    pub fn status_condition(&self) -> Option<(u16, bool)> {
         match self {
-            Self::Output => false,
-            Self::CharacterOutput => false,
-            Self::CharacterInput => false,
-            Self::Dump => false,
-            Self::Return => false,
-            _ => true,
+            Instruction::BranchIfCarrySet(_) => Some((CARRY_FLAG, true)),
+            Instruction::BranchIfCarryClear(_) => Some((CARRY_FLAG, false)),
+            Instruction::BranchIfOverflowSet(_) => Some((OVERFLOW_FLAG, true)),
+            Instruction::BranchIfOverflowClear(_) => Some((OVERFLOW_FLAG, false)),
+            Instruction::BranchIfZeroSet(_) => Some((ZERO_FLAG, true)),
+            Instruction::BranchIfZeroClear(_) => Some((ZERO_FLAG, false)),
+            Instruction::BranchIfNegative(_) => Some((NEGATIVE_FLAG, true)),
+            Instruction::BranchIfPositive(_) => Some((NEGATIVE_FLAG, false)),
+            _ => None,
         }
     }
 
@@ -69,85 +142,127 @@ impl Instruction {
             Instruction::Jump(value) => *value,
             Instruction::Exit(value) => *value,
             Instruction::Function(value) => *value,
+            Instruction::And(value) => *value,
+            Instruction::Or(value) => *value,
+            Instruction::Xor(value) => *value,
+            Instruction::ShiftLeft(value) => *value,
+            Instruction::ShiftRight(value) => *value,
+            Instruction::Modulo(value) => *value,
+            Instruction::SignedDivide(value) => *value,
+            Instruction::SignedModulo(value) => *value,
+            Instruction::SignedGreaterThan(value) => *value,
+            Instruction::SignedLessThan(value) => *value,
+            Instruction::SignedGreaterThanEqual(value) => *value,
+            Instruction::SignedLessThanEqual(value) => *value,
+            Instruction::BranchIfCarrySet(value) => *value,
+            Instruction::BranchIfCarryClear(value) => *value,
+            Instruction::BranchIfOverflowSet(value) => *value,
+            Instruction::BranchIfOverflowClear(value) => *value,
+            Instruction::BranchIfZeroSet(value) => *value,
+            Instruction::BranchIfZeroClear(value) => *value,
+            Instruction::BranchIfNegative(value) => *value,
+            Instruction::BranchIfPositive(value) => *value,
+            Instruction::Push(value) => *value,
+            Instruction::Call(value) => *value,
+            Instruction::Argument(value) => *value,
+            Instruction::Locals(value) => *value,
+            Instruction::ReturnValue(value) => *value,
+            Instruction::Page(value) => *value,
+            Instruction::Parameters(value) => *value,
             _ => panic!("Variant {:?} does not have a value", self),
         }
     }
 
-    pub fn from_string(string: &str, value: Option<Value>) -> Result<Self>
-    where
-        Self: Sized,
-    {
-        return Ok(match string {
+    pub fn from_string(string: &str, value: Option<Value>) -> Result<Self, InstructionError> {
+        let operand = || value.ok_or_else(|| InstructionError::MissingOperand(string.to_string()));
+
+        let instruction = match string {
             "OUT" => Instruction::Output,
             "CUT" => Instruction::CharacterOutput,
             "CIN" => Instruction::CharacterInput,
             "DMP" => Instruction::Dump,
             "RTN" => Instruction::Return,
-            "SEA" => Instruction::SetAddress(value.expect("SEA instruction requires value")),
-            "SET" => Instruction::SetValue(value.expect("SET instruction requires value")),
-            "ADD" => Instruction::Add(value.expect("ADD instruction requires value")),
-            "SUB" => Instruction::Subtract(value.expect("SUB instruction requires value")),
-            "MUL" => Instruction::Multiply(value.expect("MUL instruction requires value")),
-            "DIV" => Instruction::Divide(value.expect("DIV instruction requires value")),
-            "LAB" => Instruction::Label(value.expect("LAB instruction requires value")),
-            "CEQ" => Instruction::Compare(value.expect("CEQ instruction requires value")),
-            "GTN" => Instruction::GreaterThan(value.expect("GTN instruction requires value")),
-            "LTN" => Instruction::LessThan(value.expect("LTN instruction requires value")),
-            "GTE" => Instruction::GreaterThanEqual(value.expect("GTE instruction requires value")),
-            "LTE" => Instruction::LessThanEqual(value.expect("LTE instruction requires value")),
-            "BNE" => Instruction::BranchIfNotEqual(value.expect("BNE instruction requires value")),
-            "BEQ" => Instruction::BranchIfEqual(value.expect("BEQ instruction requires value")),
-            "JMP" => Instruction::Jump(value.expect("JMP instruction requires value")),
-            "EXT" => Instruction::Exit(value.expect("EXT instruction requires value")),
-            "FUN" => Instruction::Function(value.expect("FUN instruction requires value")),
-            _ => return Err(anyhow!("Unknown token {}", string)),
-        });
-    }
-
-    pub fn from_u8(instruction: [u8; 2], raw_value: Option<u16>) -> Result<Self>
-    where
-        Self: Sized,
-    {
-        let mut value: Option<Value> = None;
-        if let Some(v) = raw_value {
-            value = Some(if instruction[0] > 0x00 {
-                Value::Address(v)
-            } else {
-                Value::Literal(v)
-            });
+            "SEA" => Instruction::SetAddress(operand()?),
+            "SET" => Instruction::SetValue(operand()?),
+            "ADD" => Instruction::Add(operand()?),
+            "SUB" => Instruction::Subtract(operand()?),
+            "MUL" => Instruction::Multiply(operand()?),
+            "DIV" => Instruction::Divide(operand()?),
+            "LAB" => Instruction::Label(operand()?),
+            "CEQ" => Instruction::Compare(operand()?),
+            "GTN" => Instruction::GreaterThan(operand()?),
+            "LTN" => Instruction::LessThan(operand()?),
+            "GTE" => Instruction::GreaterThanEqual(operand()?),
+            "LTE" => Instruction::LessThanEqual(operand()?),
+            "BNE" => Instruction::BranchIfNotEqual(operand()?),
+            "BEQ" => Instruction::BranchIfEqual(operand()?),
+            "JMP" => Instruction::Jump(operand()?),
+            "EXT" => Instruction::Exit(operand()?),
+            "FUN" => Instruction::Function(operand()?),
+            "AND" => Instruction::And(operand()?),
+            "ORR" => Instruction::Or(operand()?),
+            "XOR" => Instruction::Xor(operand()?),
+            "SHL" => Instruction::ShiftLeft(operand()?),
+            "SHR" => Instruction::ShiftRight(operand()?),
+            "NOT" => Instruction::Not,
+            "MOD" => Instruction::Modulo(operand()?),
+            "SDV" => Instruction::SignedDivide(operand()?),
+            "SMD" => Instruction::SignedModulo(operand()?),
+            "SGT" => Instruction::SignedGreaterThan(operand()?),
+            "SLT" => Instruction::SignedLessThan(operand()?),
+            "SGE" => Instruction::SignedGreaterThanEqual(operand()?),
+            "SLE" => Instruction::SignedLessThanEqual(operand()?),
+            "BCS" => Instruction::BranchIfCarrySet(operand()?),
+            "BCC" => Instruction::BranchIfCarryClear(operand()?),
+            "BVS" => Instruction::BranchIfOverflowSet(operand()?),
+            "BVC" => Instruction::BranchIfOverflowClear(operand()?),
+            "BZS" => Instruction::BranchIfZeroSet(operand()?),
+            "BZC" => Instruction::BranchIfZeroClear(operand()?),
+            "BMI" => Instruction::BranchIfNegative(operand()?),
+            "BPL" => Instruction::BranchIfPositive(operand()?),
+            "PSH" => Instruction::Push(operand()?),
+            "POP" => Instruction::Pop,
+            "PEK" => Instruction::Peek,
+            "CAL" => Instruction::Call(operand()?),
+            "EFN" => Instruction::EndFunction,
+            "ARG" => Instruction::Argument(operand()?),
+            "LOC" => Instruction::Locals(operand()?),
+            "RTV" => Instruction::ReturnValue(operand()?),
+            "PAG" => Instruction::Page(operand()?),
+            "PRM" => Instruction::Parameters(operand()?),
+            _ => return Err(InstructionError::UnknownMnemonic(string.to_string())),
+        };
+
+        if value.is_some() && !instruction.requires_value() {
+            return Err(InstructionError::UnexpectedOperand(string.to_string()));
         }
 
-        let instruction: u16 = instruction[1] as u16;
-
-        Ok(match instruction {
-            0x00 => Instruction::Output,
-            0x01 => Instruction::CharacterOutput,
-            0x02 => Instruction::CharacterInput,
-            0x03 => Instruction::Dump,
-            0x04 => Instruction::Return,
-            0x05 => Instruction::SetAddress(value.expect("SEA instruction requires value")),
-            0x06 => Instruction::SetValue(value.expect("SET instruction requires value")),
-            0x07 => Instruction::Add(value.expect("ADD instruction requires value")),
-            0x08 => Instruction::Subtract(value.expect("SUB instruction requires value")),
-            0x09 => Instruction::Multiply(value.expect("MUL instruction requires value")),
-            0x0A => Instruction::Divide(value.expect("DIV instruction requires value")),
-            0x0B => Instruction::Label(value.expect("LAB instruction requires value")),
-            0x0C => Instruction::Compare(value.expect("CEQ instruction requires value")),
-            0x0D => Instruction::GreaterThan(value.expect("GTN instruction requires value")),
-            0x0E => Instruction::LessThan(value.expect("LTN instruction requires value")),
-            0x0F => Instruction::GreaterThanEqual(value.expect("GTE instruction requires value")),
-            0x10 => Instruction::LessThanEqual(value.expect("LTE instruction requires value")),
-            0x11 => Instruction::BranchIfNotEqual(value.expect("BNE instruction requires value")),
-            0x12 => Instruction::BranchIfEqual(value.expect("BEQ instruction requires value")),
-            0x13 => Instruction::Jump(value.expect("JMP instruction requires value")),
-            0x14 => Instruction::Exit(value.expect("EXT instruction requires value")),
-            0x15 => Instruction::Function(value.expect("FUN instruction requires value")),
-            _ => return Err(anyhow!("Unknown token {}", instruction)),
-        })
-    }
-
-    pub fn to_u8(&self) -> Result<u8> {
-        Ok(match self {
+        Ok(instruction)
+    }
+
+    pub fn from_u8(instruction: [u8; 2], raw_value: Option<u16>) -> Result<Self, InstructionError> {
+        let value = match (instruction[0], raw_value) {
+            (_, None) => None,
+            (0x00, Some(v)) => Some(Value::Literal(v)),
+            (0x10, Some(v)) => Some(Value::Address(v)),
+            (0x20, Some(v)) => Some(Value::Frame(v)),
+            (0x30, Some(v)) => Some(Value::FrameAddress(v)),
+            (mode, Some(_)) => return Err(InstructionError::UnknownAddressingMode(mode)),
+        };
+
+        let mnemonic = MNEMONICS
+            .get(instruction[1] as usize)
+            .ok_or(InstructionError::UnknownOpcode(instruction[1]))?;
+
+        Self::from_string(mnemonic, value)
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        MNEMONICS[self.to_u8() as usize]
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match self {
             Instruction::Output => 0x00,
             Instruction::CharacterOutput => 0x01,
             Instruction::CharacterInput => 0x02,
@@ -170,19 +285,177 @@ impl Instruction {
             Instruction::Jump(_) => 0x13,
             Instruction::Exit(_) => 0x14,
             Instruction::Function(_) => 0x15,
-        })
+            Instruction::And(_) => 0x16,
+            Instruction::Or(_) => 0x17,
+            Instruction::Xor(_) => 0x18,
+            Instruction::ShiftLeft(_) => 0x19,
+            Instruction::ShiftRight(_) => 0x1A,
+            Instruction::Not => 0x1B,
+            Instruction::Modulo(_) => 0x1C,
+            Instruction::SignedDivide(_) => 0x1D,
+            Instruction::SignedModulo(_) => 0x1E,
+            Instruction::SignedGreaterThan(_) => 0x1F,
+            Instruction::SignedLessThan(_) => 0x20,
+            Instruction::SignedGreaterThanEqual(_) => 0x21,
+            Instruction::SignedLessThanEqual(_) => 0x22,
+            Instruction::BranchIfCarrySet(_) => 0x23,
+            Instruction::BranchIfCarryClear(_) => 0x24,
+            Instruction::BranchIfOverflowSet(_) => 0x25,
+            Instruction::BranchIfOverflowClear(_) => 0x26,
+            Instruction::BranchIfZeroSet(_) => 0x27,
+            Instruction::BranchIfZeroClear(_) => 0x28,
+            Instruction::BranchIfNegative(_) => 0x29,
+            Instruction::BranchIfPositive(_) => 0x2A,
+            Instruction::Push(_) => 0x2B,
+            Instruction::Pop => 0x2C,
+            Instruction::Peek => 0x2D,
+            Instruction::Call(_) => 0x2E,
+            Instruction::EndFunction => 0x2F,
+            Instruction::Argument(_) => 0x30,
+            Instruction::Locals(_) => 0x31,
+            Instruction::ReturnValue(_) => 0x32,
+            Instruction::Page(_) => 0x33,
+            Instruction::Parameters(_) => 0x34,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InstructionError {
+    UnknownMnemonic(String),
+    UnknownOpcode(u8),
+    UnknownAddressingMode(u8),
+    MissingOperand(String),
+    UnexpectedOperand(String),
+}
+
+impl fmt::Display for InstructionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InstructionError::UnknownMnemonic(mnemonic) => write!(f, "Unknown token {}", mnemonic),
+            InstructionError::UnknownOpcode(opcode) => write!(f, "Unknown opcode {:#04x}", opcode),
+            InstructionError::UnknownAddressingMode(mode) => {
+                write!(f, "Unknown addressing mode {:#04x}", mode)
+            }
+            InstructionError::MissingOperand(mnemonic) => {
+                write!(f, "{} instruction requires value", mnemonic)
+            }
+            InstructionError::UnexpectedOperand(mnemonic) => {
+                write!(f, "{} instruction does not take a value", mnemonic)
+            }
+        }
+    }
+}
+
+impl Error for InstructionError {}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.requires_value() {
+            write!(f, "{} {}", self.mnemonic(), self.get_value())
+        } else {
+            write!(f, "{}", self.mnemonic())
+        }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Value {
     Literal(u16),
     Address(u16),
+    /// The address of a slot in the current frame, written `@n`.
+    Frame(u16),
+    /// The value in a slot of the current frame, written `*@n`.
+    FrameAddress(u16),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Literal(literal) => write!(f, "{:#06x}", literal),
+            Value::Address(address) => write!(f, "*{:#06x}", address),
+            Value::Frame(offset) => write!(f, "@{}", offset),
+            Value::FrameAddress(offset) => write!(f, "*@{}", offset),
+        }
+    }
 }
 
-pub fn get_literal_value(val: &Value, memory: &mut Memory) -> u16 {
+pub fn get_literal_value(val: &Value, memory: &Memory) -> Result<u16, Fault> {
     match val {
-        Value::Literal(literal) => *literal,
-        Value::Address(address) => memory[*address as usize],
+        Value::Literal(literal) => Ok(*literal),
+        Value::Address(address) => memory.read(*address),
+        Value::Frame(offset) => Ok(memory.frame_slot(*offset)? as u16),
+        Value::FrameAddress(offset) => Ok(memory[memory.frame_slot(*offset)?]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operand_arity() {
+        assert_eq!(
+            Instruction::from_string("SEA", None).unwrap_err(),
+            InstructionError::MissingOperand("SEA".to_string())
+        );
+        assert_eq!(
+            Instruction::from_string("OUT", Some(Value::Literal(0x01))).unwrap_err(),
+            InstructionError::UnexpectedOperand("OUT".to_string())
+        );
+        assert!(Instruction::from_string("SEA", Some(Value::Literal(0x01))).is_ok());
+        assert!(Instruction::from_string("OUT", None).is_ok());
+    }
+
+    #[test]
+    fn decode() {
+        assert_eq!(
+            Instruction::from_u8([0x00, 0x05], None).unwrap_err(),
+            InstructionError::MissingOperand("SEA".to_string())
+        );
+        assert_eq!(
+            Instruction::from_u8([0x00, 0xFF], Some(0x01)).unwrap_err(),
+            InstructionError::UnknownOpcode(0xFF)
+        );
+        assert_eq!(
+            Instruction::from_u8([0x40, 0x05], Some(0x01)).unwrap_err(),
+            InstructionError::UnknownAddressingMode(0x40)
+        );
+        assert!(matches!(
+            Instruction::from_u8([0x10, 0x05], Some(0x01)),
+            Ok(Instruction::SetAddress(Value::Address(0x01)))
+        ));
+        assert!(matches!(
+            Instruction::from_u8([0x30, 0x07], Some(0x01)),
+            Ok(Instruction::Add(Value::FrameAddress(0x01)))
+        ));
+    }
+
+    #[test]
+    fn opcodes_round_trip() {
+        for (opcode, mnemonic) in MNEMONICS.iter().enumerate() {
+            let value = Instruction::u8_requires_value(opcode as u8).then_some(Value::Literal(0));
+            let instruction = Instruction::from_string(mnemonic, value).unwrap();
+            assert_eq!(instruction.to_u8(), opcode as u8);
+            assert_eq!(instruction.mnemonic(), *mnemonic);
+        }
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Instruction::Pop.to_string(), "POP");
+        assert_eq!(
+            Instruction::SetAddress(Value::Literal(0x11)).to_string(),
+            "SEA 0x0011"
+        );
+        assert_eq!(
+            Instruction::Add(Value::Address(0x11)).to_string(),
+            "ADD *0x0011"
+        );
+        assert_eq!(Instruction::Argument(Value::Frame(2)).to_string(), "ARG @2");
+        assert_eq!(
+            Instruction::Subtract(Value::FrameAddress(1)).to_string(),
+            "SUB *@1"
+        );
     }
 }
@@ -1,162 +1,1315 @@
-use anyhow::{anyhow, Result};
 use std::{
     collections::HashMap,
-    io::{self, Write},
+    io,
     num::Wrapping,
+    time::{Duration, Instant},
 };
 
 use crate::{
+    error::{Error, Fault, RuntimeError},
     instructions::{get_literal_value, Instruction, Value},
-    Memory, ADDRESS_ADDRESS, COMPARISON_ADDRESS,
+    io::Io,
+    memory::{Memory, Register},
+    program::{FunctionEnd, Program},
+    trace::{TraceEvent, TraceFormat},
+    CARRY_FLAG, END_OF_INPUT, END_OF_INPUT_FLAG, NEGATIVE_FLAG, OVERFLOW_FLAG, ZERO_FLAG,
 };
 
-pub fn interpret(memory: &mut Memory, instructions: &mut Vec<Instruction>) -> Result<u16> {
-    let labels: HashMap<u16, usize> = instructions
-        .iter()
-        .enumerate()
-        .filter_map(|(i, instruction)| match instruction {
-            Instruction::Label(Value::Literal(literal)) => Some((*literal, i)),
-            _ => None,
-        })
-        .collect();
-
-    let functions: HashMap<u16, usize> = instructions
-        .iter()
-        .enumerate()
-        .filter_map(|(i, instruction)| match instruction {
-            Instruction::Function(Value::Literal(literal)) => Some((*literal, i)),
-            _ => None,
-        })
-        .collect();
+/// Limits on how long a program may run for, going over either of which stops
+/// the program with a fault, and whether to trace it.
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// The most instructions to run.
+    pub max_steps: Option<u64>,
+    /// The longest to run for. This is checked between instructions, so a
+    /// `CIN` waiting on a terminal is not interrupted.
+    pub timeout: Option<Duration>,
+    /// Writes a line to the program's `Io` for every instruction run.
+    pub trace: Option<TraceFormat>,
+}
 
-    let mut instruction_index = 0;
-    let mut meth_calls: Vec<u16> = Vec::new();
-    let mut caller_stack: Vec<usize> = Vec::new();
-    let term = console::Term::stdout();
+/// How many instructions to run between checks of the timeout.
+const TIMEOUT_INTERVAL: u64 = 1024;
 
-    while instruction_index < instructions.len() {
-        let instruction = &instructions[instruction_index];
-        match instruction {
-            Instruction::Output => {
-                print!("{}", memory[memory[ADDRESS_ADDRESS] as usize]);
-                io::stdout().flush()?;
+pub fn interpret(memory: &mut Memory, program: &Program, io: &mut dyn Io) -> Result<u16, Error> {
+    interpret_with_options(memory, program, io, &RunOptions::default())
+}
+
+pub fn interpret_with_options(
+    memory: &mut Memory,
+    program: &Program,
+    io: &mut dyn Io,
+    options: &RunOptions,
+) -> Result<u16, Error> {
+    let start = Instant::now();
+    memory.set_recording(options.trace.is_some());
+    let mut machine = Machine::new(memory, program, io);
+    if let Err(fault) = machine.memory.load(&program.data) {
+        return Err(machine.runtime_error(fault));
+    }
+
+    let mut steps: u64 = 0;
+    while machine.index < program.instructions.len() {
+        if options
+            .max_steps
+            .is_some_and(|max_steps| steps >= max_steps)
+        {
+            return Err(machine.runtime_error(Fault::StepLimit(steps)));
+        }
+
+        if let Some(timeout) = options.timeout {
+            if steps.is_multiple_of(TIMEOUT_INTERVAL) && start.elapsed() > timeout {
+                return Err(machine.runtime_error(Fault::Timeout(timeout)));
             }
+        }
+
+        let event = options.trace.map(|_| machine.trace_event());
+        let result = machine.step();
+        if let (Some(format), Some(mut event)) = (options.trace, event) {
+            event.changes = machine.memory.take_changes();
+            machine.io.trace(&event.format(format))?;
+        }
+
+        match result {
+            Ok(None) => {}
+            Ok(Some(code)) => return Ok(code),
+            Err(Stop::Fault(fault)) => return Err(machine.runtime_error(fault)),
+            Err(Stop::Io(err)) => return Err(err.into()),
+        }
+
+        steps += 1;
+    }
+
+    Ok(0)
+}
+
+/// Why a step ended the program early.
+enum Stop {
+    Fault(Fault),
+    Io(io::Error),
+}
+
+impl From<Fault> for Stop {
+    fn from(fault: Fault) -> Self {
+        Stop::Fault(fault)
+    }
+}
+
+impl From<io::Error> for Stop {
+    fn from(err: io::Error) -> Self {
+        Stop::Io(err)
+    }
+}
+
+/// A function call in progress.
+struct Call {
+    /// The index of the CAL instruction.
+    caller: usize,
+    /// The caller's frame pointer, restored on return.
+    frame_pointer: u16,
+    /// How many values the caller passed, from its first `ARG` on.
+    arguments: u16,
+}
+
+/// A program being run, along with everything it needs besides memory.
+struct Machine<'a> {
+    program: &'a Program,
+    memory: &'a mut Memory,
+    io: &'a mut dyn Io,
+    labels: HashMap<u16, usize>,
+    functions: HashMap<u16, usize>,
+    function_ends: HashMap<usize, FunctionEnd>,
+    calls: Vec<Call>,
+    /// The stack depth at the first `ARG` since the last call or return.
+    arguments: Option<u16>,
+    /// The index of the next instruction to run.
+    index: usize,
+}
+
+impl<'a> Machine<'a> {
+    fn new(memory: &'a mut Memory, program: &'a Program, io: &'a mut dyn Io) -> Self {
+        let labels = program
+            .instructions
+            .iter()
+            .enumerate()
+            .filter_map(|(i, instruction)| match instruction {
+                Instruction::Label(Value::Literal(literal)) => Some((*literal, i)),
+                _ => None,
+            })
+            .collect();
+
+        let functions = program
+            .instructions
+            .iter()
+            .enumerate()
+            .filter_map(|(i, instruction)| match instruction {
+                Instruction::Function(Value::Literal(literal)) => Some((*literal, i)),
+                _ => None,
+            })
+            .collect();
+
+        Self {
+            program,
+            memory,
+            io,
+            labels,
+            functions,
+            function_ends: program.function_ends(),
+            calls: Vec::new(),
+            arguments: None,
+            index: 0,
+        }
+    }
+
+    /// Runs the next instruction, returning the exit code if it ended the
+    /// program.
+    fn step(&mut self) -> Result<Option<u16>, Stop> {
+        let program = self.program;
+        let instruction = &program.instructions[self.index];
+
+        match instruction {
+            Instruction::Output => self.io.write(&self.memory.current()?.to_string())?,
             Instruction::CharacterOutput => {
-                print!(
-                    "{}",
-                    char::from_u32(memory[memory[ADDRESS_ADDRESS] as usize] as u32)
-                        .expect("Invalid character")
-                );
-                io::stdout().flush()?;
-            }
-            Instruction::CharacterInput => {
-                let char = console::Term::read_char(&term)?;
-                memory[memory[ADDRESS_ADDRESS] as usize] = char as u32 as u16;
-                print!("{}", char);
-            }
-            Instruction::Dump => println!("{:?}", memory),
-            Instruction::Return => {
-                if caller_stack.len() == 0 {
-                    return Err(anyhow!("No caller to return to"));
+                let value = self.memory.current()?;
+                let char = char::from_u32(value as u32).ok_or(Fault::InvalidCharacter(value))?;
+
+                self.io.write(&char.to_string())?;
+            }
+            Instruction::CharacterInput => match self.io.read_char()? {
+                Some(char) => {
+                    let value =
+                        u16::try_from(char as u32).map_err(|_| Fault::WideCharacter(char))?;
+                    self.memory.set_current(value)?;
+                }
+                None => {
+                    self.memory.set_current(END_OF_INPUT)?;
+                    let status = self.memory.register(Register::Status);
+                    self.memory
+                        .set_register(Register::Status, status | END_OF_INPUT_FLAG);
                 }
+            },
+            Instruction::Dump => self.io.write(&format!("{:?}\n", self.memory.cells()))?,
+            Instruction::Return | Instruction::EndFunction | Instruction::ReturnValue(_) => {
+                let value = match instruction {
+                    Instruction::ReturnValue(value) => Some(self.value(value)?),
+                    _ => None,
+                };
+
+                let call = self.calls.pop().ok_or(Fault::NoCaller)?;
+                self.arguments = None;
+
+                // Drop the frame's arguments and locals
+                let frame_pointer = self.memory.register(Register::FramePointer);
+                self.memory
+                    .set_register(Register::StackPointer, frame_pointer);
+                self.memory
+                    .set_register(Register::FramePointer, call.frame_pointer);
 
-                if meth_calls.len() > 0 {
-                    meth_calls.pop();
+                if let Some(value) = value {
+                    self.memory.push(value)?;
                 }
 
-                instruction_index = caller_stack.pop().unwrap();
+                self.index = call.caller;
             }
             Instruction::SetAddress(new_address) => {
-                memory[ADDRESS_ADDRESS] = get_literal_value(new_address, memory)
+                let new_address = self.value(new_address)?;
+                self.memory.set_register(Register::Address, new_address);
             }
             Instruction::SetValue(value) => {
-                memory[memory[ADDRESS_ADDRESS] as usize] = get_literal_value(value, memory)
+                let value = self.value(value)?;
+                self.memory.set_current(value)?;
+            }
+            Instruction::Page(page) => {
+                let page = self.value(page)?;
+                self.memory.set_page(page);
             }
             Instruction::Add(other) => {
-                memory[memory[ADDRESS_ADDRESS] as usize] =
-                    (Wrapping(memory[memory[ADDRESS_ADDRESS] as usize])
-                        + Wrapping(get_literal_value(other, memory)))
-                    .0
+                let value = self.value(other)?;
+                self.arithmetic(value, |cell, value| {
+                    let (result, carry) = cell.overflowing_add(value);
+                    (result, carry, (cell as i16).overflowing_add(value as i16).1)
+                })?;
             }
             Instruction::Subtract(other) => {
-                memory[memory[ADDRESS_ADDRESS] as usize] =
-                    (Wrapping(memory[memory[ADDRESS_ADDRESS] as usize])
-                        - Wrapping(get_literal_value(other, memory)))
-                    .0
+                let value = self.value(other)?;
+                self.arithmetic(value, |cell, value| {
+                    let (result, borrow) = cell.overflowing_sub(value);
+                    (
+                        result,
+                        borrow,
+                        (cell as i16).overflowing_sub(value as i16).1,
+                    )
+                })?;
             }
             Instruction::Multiply(other) => {
-                memory[memory[ADDRESS_ADDRESS] as usize] =
-                    (Wrapping(memory[memory[ADDRESS_ADDRESS] as usize])
-                        * Wrapping(get_literal_value(other, memory)))
-                    .0
+                let value = self.value(other)?;
+                self.arithmetic(value, |cell, value| {
+                    let (result, carry) = cell.overflowing_mul(value);
+                    (result, carry, (cell as i16).overflowing_mul(value as i16).1)
+                })?;
             }
             Instruction::Divide(other) => {
-                memory[memory[ADDRESS_ADDRESS] as usize] =
-                    (Wrapping(memory[memory[ADDRESS_ADDRESS] as usize])
-                        / Wrapping(get_literal_value(other, memory)))
-                    .0
+                let value = self.divisor(other)?;
+                self.arithmetic(value, |cell, value| (cell / value, false, false))?;
+            }
+            Instruction::Modulo(other) => {
+                let value = self.divisor(other)?;
+                self.arithmetic(value, |cell, value| (cell % value, false, false))?;
+            }
+            // The signed instructions treat cells as two's complement, so
+            // -32768 / -1 wraps back round to -32768 and sets the overflow flag
+            Instruction::SignedDivide(other) => {
+                let value = self.divisor(other)?;
+                self.arithmetic(value, |cell, value| {
+                    let (result, overflow) = (cell as i16).overflowing_div(value as i16);
+                    (result as u16, false, overflow)
+                })?;
+            }
+            Instruction::SignedModulo(other) => {
+                let value = self.divisor(other)?;
+                self.arithmetic(value, |cell, value| {
+                    let (result, overflow) = (cell as i16).overflowing_rem(value as i16);
+                    (result as u16, false, overflow)
+                })?;
+            }
+            Instruction::And(other) => {
+                let value = self.value(other)?;
+                self.update(|cell| (Wrapping(cell) & Wrapping(value)).0)?;
+            }
+            Instruction::Or(other) => {
+                let value = self.value(other)?;
+                self.update(|cell| (Wrapping(cell) | Wrapping(value)).0)?;
+            }
+            Instruction::Xor(other) => {
+                let value = self.value(other)?;
+                self.update(|cell| (Wrapping(cell) ^ Wrapping(value)).0)?;
+            }
+            // Like the arithmetic, shifts wrap: only the low four bits of the
+            // shift amount are used
+            Instruction::ShiftLeft(other) => {
+                let value = self.value(other)?;
+                self.update(|cell| (Wrapping(cell) << value as usize).0)?;
+            }
+            Instruction::ShiftRight(other) => {
+                let value = self.value(other)?;
+                self.update(|cell| (Wrapping(cell) >> value as usize).0)?;
             }
+            Instruction::Not => self.update(|cell| (!Wrapping(cell)).0)?,
             Instruction::Compare(other) => {
-                let value = get_literal_value(other, memory);
-                memory[COMPARISON_ADDRESS] =
-                    (memory[memory[ADDRESS_ADDRESS] as usize] == value) as u16;
-            }
-            Instruction::BranchIfNotEqual(label) => {
-                if memory[COMPARISON_ADDRESS] == 0 {
-                    instruction_index = *labels.get(&get_literal_value(label, memory)).expect(
-                        format!(
-                            "Use of undeclared label {}",
-                            get_literal_value(label, memory)
-                        )
-                        .as_str(),
-                    );
-                    continue;
-                }
+                let value = self.value(other)?;
+                self.compare(|cell| cell == value)?;
             }
-            Instruction::BranchIfEqual(label) => {
-                if memory[COMPARISON_ADDRESS] == 1 {
-                    instruction_index = *labels.get(&get_literal_value(label, memory)).expect(
-                        format!(
-                            "Use of undeclared label {}",
-                            get_literal_value(label, memory)
-                        )
-                        .as_str(),
-                    );
-                    continue;
-                }
+            Instruction::GreaterThan(other) => {
+                let value = self.value(other)?;
+                self.compare(|cell| cell > value)?;
+            }
+            Instruction::LessThan(other) => {
+                let value = self.value(other)?;
+                self.compare(|cell| cell < value)?;
+            }
+            Instruction::GreaterThanEqual(other) => {
+                let value = self.value(other)?;
+                self.compare(|cell| cell >= value)?;
+            }
+            Instruction::LessThanEqual(other) => {
+                let value = self.value(other)?;
+                self.compare(|cell| cell <= value)?;
+            }
+            Instruction::SignedGreaterThan(other) => {
+                let value = self.value(other)?;
+                self.compare(|cell| (cell as i16) > (value as i16))?;
+            }
+            Instruction::SignedLessThan(other) => {
+                let value = self.value(other)?;
+                self.compare(|cell| (cell as i16) < (value as i16))?;
             }
-            Instruction::Jump(label) => {
-                caller_stack.push(instruction_index);
-                let value = get_literal_value(label, memory);
-
-                if let Some(meth_instruction_index) = functions.get(&value) {
-                    meth_calls.push(value);
-                    instruction_index = *meth_instruction_index;
-                } else if let Some(lab_instruction_index) = labels.get(&value) {
-                    instruction_index = *lab_instruction_index;
-                } else {
-                    return Err(anyhow!(format!("Unknown label/method {}", value)));
+            Instruction::SignedGreaterThanEqual(other) => {
+                let value = self.value(other)?;
+                self.compare(|cell| (cell as i16) >= (value as i16))?;
+            }
+            Instruction::SignedLessThanEqual(other) => {
+                let value = self.value(other)?;
+                self.compare(|cell| (cell as i16) <= (value as i16))?;
+            }
+            instruction if self.branches(instruction) => {
+                let label = self.value(&instruction.get_value())?;
+                self.index = *self
+                    .labels
+                    .get(&label)
+                    .ok_or(Fault::UndeclaredLabel(label))?;
+                return Ok(None);
+            }
+            Instruction::Call(function) => {
+                let function = self.value(function)?;
+                self.call(function)?;
+                return Ok(None);
+            }
+            Instruction::Push(value) => {
+                let value = self.value(value)?;
+                self.memory.push(value)?;
+            }
+            Instruction::Argument(value) => {
+                let value = self.value(value)?;
+                if self.arguments.is_none() {
+                    self.arguments = Some(self.memory.register(Register::StackPointer));
                 }
 
-                continue;
+                self.memory.push(value)?;
             }
-            Instruction::Function(label) => {
-                let label = get_literal_value(label, memory);
-                if meth_calls.len() == 0 || meth_calls[meth_calls.len() - 1] != label.into() {
-                    while !matches!(instructions[instruction_index], Instruction::Return) {
-                        if instruction_index >= instructions.len() {
-                            return Err(anyhow!("RTN not found for method {}", label));
-                        }
-
-                        instruction_index += 1;
+            // Moves the frame down over the last values the caller passed, so
+            // they become the first slots
+            Instruction::Parameters(count) => {
+                let count = self.value(count)?;
+                let call = self.calls.last().ok_or(Fault::NoCaller)?;
+                if count > call.arguments {
+                    return Err(Fault::MissingArguments {
+                        expected: count,
+                        passed: call.arguments,
                     }
+                    .into());
                 }
+
+                let frame_pointer = self.memory.register(Register::FramePointer);
+
+                self.memory
+                    .set_register(Register::FramePointer, frame_pointer - count);
+            }
+            Instruction::Locals(count) => {
+                let count = self.value(count)?;
+                self.memory.reserve(count)?;
+            }
+            Instruction::Pop => {
+                let value = self.memory.peek()?;
+                self.memory.set_current(value)?;
+                self.memory.pop()?;
+            }
+            Instruction::Peek => {
+                let value = self.memory.peek()?;
+                self.memory.set_current(value)?;
             }
+            Instruction::Exit(code) => return Ok(Some(self.value(code)?)),
+            // Functions are entered by CAL, so skip over their bodies otherwise
+            Instruction::Function(_) => match self.function_ends[&self.index] {
+                FunctionEnd::Marker(end) => self.index = end,
+                FunctionEnd::Nested(_) | FunctionEnd::Missing => {
+                    return Err(Fault::MissingFunctionEnd.into());
+                }
+            },
             _ => {}
         }
 
-        instruction_index += 1;
+        self.index += 1;
+        Ok(None)
     }
 
-    Ok(0)
+    /// Describes the next instruction, before it is run.
+    fn trace_event(&mut self) -> TraceEvent<'a> {
+        let program: &'a Program = self.program;
+        let instruction = &program.instructions[self.index];
+        self.memory.take_changes();
+
+        TraceEvent {
+            index: self.index,
+            span: program.spans.get(self.index),
+            instruction,
+            operand: instruction
+                .requires_value()
+                .then(|| self.value(&instruction.get_value()).ok())
+                .flatten(),
+            address: self.memory.register(Register::Address),
+            changes: Vec::new(),
+            depth: self.calls.len(),
+        }
+    }
+
+    /// Enters `function` with an empty frame at the top of the stack. The
+    /// function takes its arguments from below that with `PRM`, out of the
+    /// values pushed since the first `ARG`.
+    fn call(&mut self, function: u16) -> Result<(), Fault> {
+        let function_index = *self
+            .functions
+            .get(&function)
+            .ok_or(Fault::UndeclaredFunction(function))?;
+
+        let stack_pointer = self.memory.register(Register::StackPointer);
+        let arguments = self
+            .arguments
+            .take()
+            .map_or(0, |start| stack_pointer.saturating_sub(start));
+        self.calls.push(Call {
+            caller: self.index,
+            frame_pointer: self.memory.register(Register::FramePointer),
+            arguments,
+        });
+        self.memory
+            .set_register(Register::FramePointer, stack_pointer);
+        self.index = function_index + 1;
+        Ok(())
+    }
+
+    fn value(&self, value: &Value) -> Result<u16, Fault> {
+        get_literal_value(value, self.memory)
+    }
+
+    fn divisor(&self, value: &Value) -> Result<u16, Fault> {
+        match self.value(value)? {
+            0 => Err(Fault::DivisionByZero),
+            divisor => Ok(divisor),
+        }
+    }
+
+    /// Whether `instruction` is a jump, or a branch whose condition holds.
+    fn branches(&self, instruction: &Instruction) -> bool {
+        let comparison = self.memory.register(Register::Comparison);
+        let status = self.memory.register(Register::Status);
+
+        match instruction {
+            Instruction::Jump(_) => true,
+            Instruction::BranchIfNotEqual(_) => comparison == 0,
+            Instruction::BranchIfEqual(_) => comparison == 1,
+            instruction => instruction
+                .status_condition()
+                .is_some_and(|(flag, set)| (status & flag != 0) == set),
+        }
+    }
+
+    /// Replaces the current cell with `operation` applied to it.
+    fn update(&mut self, operation: impl Fn(u16) -> u16) -> Result<(), Fault> {
+        let cell = self.memory.current()?;
+        self.memory.set_current(operation(cell))
+    }
+
+    /// Stores whether `comparison` holds for the current cell.
+    fn compare(&mut self, comparison: impl Fn(u16) -> bool) -> Result<(), Fault> {
+        let result = comparison(self.memory.current()?);
+        self.memory
+            .set_register(Register::Comparison, result as u16);
+        Ok(())
+    }
+
+    /// Applies `operation` to the current cell and `value`, storing the result
+    /// and setting the status flags from it and the carry and overflow it
+    /// reports. The end of input flag is left as it is.
+    fn arithmetic(
+        &mut self,
+        value: u16,
+        operation: impl Fn(u16, u16) -> (u16, bool, bool),
+    ) -> Result<(), Fault> {
+        let (result, carry, overflow) = operation(self.memory.current()?, value);
+
+        let mut status = self.memory.register(Register::Status) & END_OF_INPUT_FLAG;
+        if carry {
+            status |= CARRY_FLAG;
+        }
+        if overflow {
+            status |= OVERFLOW_FLAG;
+        }
+        if result == 0 {
+            status |= ZERO_FLAG;
+        }
+        if result & 0x8000 != 0 {
+            status |= NEGATIVE_FLAG;
+        }
+
+        self.memory.set_current(result)?;
+        self.memory.set_register(Register::Status, status);
+        Ok(())
+    }
+
+    /// Builds an error pointing at the instruction that faulted and each of
+    /// the calls that led to it.
+    fn runtime_error(&self, fault: Fault) -> Error {
+        let program = self.program;
+        let spans = program.spans.get(self.index).map(|span| {
+            let call_spans = self
+                .calls
+                .iter()
+                .rev()
+                .filter_map(|call| program.spans.get(call.caller).cloned())
+                .collect();
+
+            (span.clone(), call_spans)
+        });
+
+        RuntimeError {
+            fault,
+            index: self.index,
+            address: self.memory.register(Register::Address),
+            calls: self.calls.iter().rev().map(|call| call.caller).collect(),
+            spans,
+        }
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        diagnostic::SourceFile, io::Buffer, lexer::lex_source, program::DataBlock, PAGE_SIZE,
+        STACK_SIZE,
+    };
+
+    fn program(instructions: Vec<Instruction>) -> Program {
+        Program {
+            instructions,
+            ..Default::default()
+        }
+    }
+
+    fn compare(cell: u16, instruction: fn(Value) -> Instruction, other: u16) -> u16 {
+        let memory = &mut Memory::default();
+        let program = program(vec![
+            Instruction::SetAddress(Value::Literal(0x00)),
+            Instruction::SetValue(Value::Literal(cell)),
+            instruction(Value::Literal(other)),
+        ]);
+
+        interpret(memory, &program, &mut Buffer::default()).unwrap();
+        memory.register(Register::Comparison)
+    }
+
+    #[test]
+    fn greater_than() {
+        assert_eq!(compare(0x0001, Instruction::GreaterThan, 0x0000), 1);
+        assert_eq!(compare(0x0000, Instruction::GreaterThan, 0x0000), 0);
+        assert_eq!(compare(0x0000, Instruction::GreaterThan, 0xFFFF), 0);
+        assert_eq!(compare(0xFFFF, Instruction::GreaterThan, 0x0000), 1);
+        assert_eq!(compare(0xFFFF, Instruction::GreaterThan, 0xFFFF), 0);
+        assert_eq!(compare(0xFFFE, Instruction::GreaterThan, 0xFFFF), 0);
+    }
+
+    #[test]
+    fn less_than() {
+        assert_eq!(compare(0x0000, Instruction::LessThan, 0x0001), 1);
+        assert_eq!(compare(0x0000, Instruction::LessThan, 0x0000), 0);
+        assert_eq!(compare(0x0000, Instruction::LessThan, 0xFFFF), 1);
+        assert_eq!(compare(0xFFFF, Instruction::LessThan, 0x0000), 0);
+        assert_eq!(compare(0xFFFF, Instruction::LessThan, 0xFFFF), 0);
+        assert_eq!(compare(0xFFFE, Instruction::LessThan, 0xFFFF), 1);
+    }
+
+    #[test]
+    fn greater_than_equal() {
+        assert_eq!(compare(0x0001, Instruction::GreaterThanEqual, 0x0000), 1);
+        assert_eq!(compare(0x0000, Instruction::GreaterThanEqual, 0x0000), 1);
+        assert_eq!(compare(0x0000, Instruction::GreaterThanEqual, 0xFFFF), 0);
+        assert_eq!(compare(0xFFFF, Instruction::GreaterThanEqual, 0x0000), 1);
+        assert_eq!(compare(0xFFFF, Instruction::GreaterThanEqual, 0xFFFF), 1);
+        assert_eq!(compare(0xFFFE, Instruction::GreaterThanEqual, 0xFFFF), 0);
+    }
+
+    #[test]
+    fn less_than_equal() {
+        assert_eq!(compare(0x0000, Instruction::LessThanEqual, 0x0001), 1);
+        assert_eq!(compare(0x0000, Instruction::LessThanEqual, 0x0000), 1);
+        assert_eq!(compare(0x0000, Instruction::LessThanEqual, 0xFFFF), 1);
+        assert_eq!(compare(0xFFFF, Instruction::LessThanEqual, 0x0000), 0);
+        assert_eq!(compare(0xFFFF, Instruction::LessThanEqual, 0xFFFF), 1);
+        assert_eq!(compare(0xFFFE, Instruction::LessThanEqual, 0xFFFF), 1);
+    }
+
+    fn apply(cell: u16, instruction: Instruction) -> u16 {
+        let memory = &mut Memory::default();
+        let program = program(vec![
+            Instruction::SetAddress(Value::Literal(0x01)),
+            Instruction::SetValue(Value::Literal(0x0004)),
+            Instruction::SetAddress(Value::Literal(0x00)),
+            Instruction::SetValue(Value::Literal(cell)),
+            instruction,
+        ]);
+
+        interpret(memory, &program, &mut Buffer::default()).unwrap();
+        memory[0x00]
+    }
+
+    #[test]
+    fn bitwise() {
+        assert_eq!(
+            apply(0b1100, Instruction::And(Value::Literal(0b1010))),
+            0b1000
+        );
+        assert_eq!(
+            apply(0b1100, Instruction::Or(Value::Literal(0b1010))),
+            0b1110
+        );
+        assert_eq!(
+            apply(0b1100, Instruction::Xor(Value::Literal(0b1010))),
+            0b0110
+        );
+        assert_eq!(
+            apply(0b1100, Instruction::And(Value::Address(0x01))),
+            0b0100
+        );
+        assert_eq!(apply(0x00FF, Instruction::Not), 0xFF00);
+    }
+
+    #[test]
+    fn division() {
+        assert_eq!(
+            apply(0x0011, Instruction::Modulo(Value::Literal(5))),
+            0x0002
+        );
+        assert_eq!(
+            apply(0x0013, Instruction::Modulo(Value::Address(0x01))),
+            0x0003
+        );
+        assert_eq!(
+            apply(0xFFF9, Instruction::Modulo(Value::Literal(2))),
+            0x0001
+        );
+        assert_eq!(
+            apply(0xFFF9, Instruction::SignedDivide(Value::Literal(2))),
+            0xFFFD
+        );
+        assert_eq!(
+            apply(0xFFF9, Instruction::SignedModulo(Value::Literal(2))),
+            0xFFFF
+        );
+        assert_eq!(
+            apply(0x0007, Instruction::SignedModulo(Value::Literal(0xFFFE))),
+            0x0001
+        );
+        assert_eq!(
+            apply(0x8000, Instruction::SignedDivide(Value::Literal(0xFFFF))),
+            0x8000
+        );
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let memory = &mut Memory::default();
+        let program = program(vec![
+            Instruction::SetAddress(Value::Literal(0x10)),
+            Instruction::Call(Value::Literal(0x01)),
+            Instruction::Function(Value::Literal(0x01)),
+            Instruction::Modulo(Value::Address(0x11)),
+            Instruction::EndFunction,
+        ]);
+
+        let err = interpret(memory, &program, &mut Buffer::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "error: Division by zero (ADDRESS is 0x0010) at instruction 3, called from instruction 1"
+        );
+        assert!(matches!(
+            err,
+            Error::Runtime(RuntimeError {
+                fault: Fault::DivisionByZero,
+                index: 3,
+                address: 0x10,
+                ref calls,
+                spans: None,
+            }) if calls == &[1]
+        ));
+    }
+
+    #[test]
+    fn division_by_zero_in_source() {
+        let memory = &mut Memory::default();
+        let program = lex_source(SourceFile {
+            path: "test.jasm".to_string(),
+            text: "CAL divide\nEXT 0x00\nFUN divide\nDIV 0x00\nEFN\n".to_string(),
+        })
+        .unwrap();
+
+        let message = interpret(memory, &program, &mut Buffer::default())
+            .unwrap_err()
+            .to_string();
+        assert!(
+            message.starts_with("error: Division by zero (ADDRESS is 0x0000)\n --> test.jasm:4:1")
+        );
+        assert!(message.contains("note: Called from here\n --> test.jasm:1:1"));
+    }
+
+    fn status(cell: u16, instruction: Instruction) -> u16 {
+        let memory = &mut Memory::default();
+        let program = program(vec![
+            Instruction::SetAddress(Value::Literal(0x00)),
+            Instruction::SetValue(Value::Literal(cell)),
+            instruction,
+        ]);
+
+        interpret(memory, &program, &mut Buffer::default()).unwrap();
+        memory.register(Register::Status)
+    }
+
+    #[test]
+    fn status_flags() {
+        assert_eq!(status(0x0001, Instruction::Add(Value::Literal(0x0001))), 0);
+        assert_eq!(
+            status(0xFFFF, Instruction::Add(Value::Literal(0x0001))),
+            CARRY_FLAG | ZERO_FLAG
+        );
+        assert_eq!(
+            status(0x7FFF, Instruction::Add(Value::Literal(0x0001))),
+            OVERFLOW_FLAG | NEGATIVE_FLAG
+        );
+        assert_eq!(
+            status(0x0000, Instruction::Subtract(Value::Literal(0x0001))),
+            CARRY_FLAG | NEGATIVE_FLAG
+        );
+        assert_eq!(
+            status(0x8000, Instruction::Subtract(Value::Literal(0x0001))),
+            OVERFLOW_FLAG
+        );
+        assert_eq!(
+            status(0x0100, Instruction::Multiply(Value::Literal(0x0100))),
+            CARRY_FLAG | OVERFLOW_FLAG | ZERO_FLAG
+        );
+        assert_eq!(
+            status(0x8000, Instruction::SignedDivide(Value::Literal(0xFFFF))),
+            OVERFLOW_FLAG | NEGATIVE_FLAG
+        );
+        assert_eq!(
+            status(0x0004, Instruction::Modulo(Value::Literal(0x0002))),
+            ZERO_FLAG
+        );
+    }
+
+    #[test]
+    fn status_branches() {
+        let memory = &mut Memory::default();
+        let program = program(vec![
+            Instruction::SetAddress(Value::Literal(0x00)),
+            Instruction::SetValue(Value::Literal(0xFFFF)),
+            Instruction::Add(Value::Literal(0x0002)),
+            Instruction::BranchIfCarryClear(Value::Literal(0x00)),
+            Instruction::BranchIfNegative(Value::Literal(0x00)),
+            Instruction::BranchIfCarrySet(Value::Literal(0x01)),
+            Instruction::Label(Value::Literal(0x00)),
+            Instruction::Exit(Value::Literal(0x01)),
+            Instruction::Label(Value::Literal(0x01)),
+            Instruction::Exit(Value::Address(0x00)),
+        ]);
+
+        assert_eq!(
+            interpret(memory, &program, &mut Buffer::default()).unwrap(),
+            0x0001
+        );
+    }
+
+    #[test]
+    fn shifts() {
+        assert_eq!(
+            apply(0x0001, Instruction::ShiftLeft(Value::Literal(4))),
+            0x0010
+        );
+        assert_eq!(
+            apply(0x8001, Instruction::ShiftLeft(Value::Literal(1))),
+            0x0002
+        );
+        assert_eq!(
+            apply(0x8000, Instruction::ShiftRight(Value::Address(0x01))),
+            0x0800
+        );
+        assert_eq!(
+            apply(0x0001, Instruction::ShiftLeft(Value::Literal(17))),
+            0x0002
+        );
+    }
+
+    #[test]
+    fn signed_comparisons() {
+        assert_eq!(compare(0x0001, Instruction::SignedGreaterThan, 0xFFFF), 1);
+        assert_eq!(compare(0xFFFF, Instruction::SignedGreaterThan, 0x0001), 0);
+        assert_eq!(compare(0x8000, Instruction::SignedLessThan, 0x7FFF), 1);
+        assert_eq!(compare(0xFFFF, Instruction::SignedLessThan, 0xFFFE), 0);
+        assert_eq!(
+            compare(0xFFFF, Instruction::SignedGreaterThanEqual, 0xFFFF),
+            1
+        );
+        assert_eq!(
+            compare(0xFFFE, Instruction::SignedGreaterThanEqual, 0x0000),
+            0
+        );
+        assert_eq!(compare(0xFFFE, Instruction::SignedLessThanEqual, 0xFFFF), 1);
+        assert_eq!(compare(0x0000, Instruction::SignedLessThanEqual, 0x8000), 0);
+    }
+
+    #[test]
+    fn comparison_against_address() {
+        let memory = &mut Memory::default();
+        let program = program(vec![
+            Instruction::SetAddress(Value::Literal(0x01)),
+            Instruction::SetValue(Value::Literal(0xFFFF)),
+            Instruction::SetAddress(Value::Literal(0x00)),
+            Instruction::LessThan(Value::Address(0x01)),
+        ]);
+
+        interpret(memory, &program, &mut Buffer::default()).unwrap();
+        assert_eq!(memory.register(Register::Comparison), 1);
+    }
+
+    #[test]
+    fn stack() {
+        let memory = &mut Memory::default();
+        let program = program(vec![
+            Instruction::SetAddress(Value::Literal(0x00)),
+            Instruction::SetValue(Value::Literal(0x03)),
+            Instruction::Push(Value::Literal(0x01)),
+            Instruction::Push(Value::Address(0x00)),
+            Instruction::Peek,
+            Instruction::SetAddress(Value::Literal(0x01)),
+            Instruction::Pop,
+            Instruction::SetAddress(Value::Literal(0x02)),
+            Instruction::Pop,
+        ]);
+
+        interpret(memory, &program, &mut Buffer::default()).unwrap();
+        assert_eq!(memory.cells()[0x00..0x03], [0x03, 0x03, 0x01]);
+        assert_eq!(memory.register(Register::StackPointer), 0);
+    }
+
+    #[test]
+    fn stack_overflow_and_underflow() {
+        let memory = &mut Memory::default();
+        let overflow = program(vec![
+            Instruction::Label(Value::Literal(0x00)),
+            Instruction::Push(Value::Literal(0x01)),
+            Instruction::Compare(Value::Literal(0x01)),
+            Instruction::BranchIfNotEqual(Value::Literal(0x00)),
+        ]);
+
+        assert_eq!(
+            interpret(memory, &overflow, &mut Buffer::default())
+                .unwrap_err()
+                .to_string(),
+            "error: Stack overflow (ADDRESS is 0x0000) at instruction 1"
+        );
+        assert_eq!(memory.register(Register::StackPointer) as usize, STACK_SIZE);
+
+        let memory = &mut Memory::default();
+        let underflow = program(vec![
+            Instruction::Push(Value::Literal(0x01)),
+            Instruction::Pop,
+            Instruction::Peek,
+        ]);
+
+        assert_eq!(
+            interpret(memory, &underflow, &mut Buffer::default())
+                .unwrap_err()
+                .to_string(),
+            "error: Stack underflow (ADDRESS is 0x0000) at instruction 2"
+        );
+    }
+
+    #[test]
+    fn recursion_with_frames() {
+        let memory = &mut Memory::default();
+        // Sums the numbers from 1 to n
+        let program = lex_source(SourceFile {
+            path: "test.jasm".to_string(),
+            text: "FUN 0x01
+                PRM 0x01
+                SEA @0
+                CEQ 0x00
+                BNE 0x02
+                RTV 0x00
+            LAB 0x02
+                LOC 0x01
+                SEA @1
+                SET *@0
+                SUB 0x01
+                ARG *@1
+                CAL 0x01
+                SEA @1
+                POP
+                ADD *@0
+                RTV *@1
+            EFN
+            ARG 0x05
+            CAL 0x01
+            SEA 0x00
+            POP
+            EXT *0x00"
+                .to_string(),
+        })
+        .unwrap();
+
+        assert_eq!(
+            interpret(memory, &program, &mut Buffer::default()).unwrap(),
+            15
+        );
+        assert_eq!(memory.register(Register::StackPointer), 0);
+        assert_eq!(memory.register(Register::FramePointer), 0);
+    }
+
+    #[test]
+    fn arguments_are_declared_by_the_callee() {
+        let run = |text: &str| {
+            let program = lex_source(SourceFile {
+                path: "test.jasm".to_string(),
+                text: text.to_string(),
+            })
+            .unwrap();
+            let memory = &mut Memory::default();
+            let exit_code = interpret(memory, &program, &mut Buffer::default());
+            (exit_code, memory.register(Register::StackPointer))
+        };
+
+        // Values pushed after the first argument are passed too
+        let (exit_code, stack_pointer) = run("FUN sub\nPRM 2\nSEA @0\nSUB *@1\nRTV *@0\nEFN
+            ARG 10\nPSH 7\nCAL sub\nSEA 0\nPOP\nEXT *0\n");
+        assert_eq!(exit_code.unwrap(), 3);
+        assert_eq!(stack_pointer, 0);
+
+        // Each call only passes the arguments pushed for it
+        let (exit_code, _) = run("FUN sub\nPRM 2\nEFN\nFUN seven\nRTV 7\nEFN
+            ARG 10\nCAL seven\nCAL sub\n");
+        assert!(exit_code
+            .unwrap_err()
+            .to_string()
+            .contains("Function takes 2 argument(s) but only 0 were passed"));
+
+        // The caller's own values aren't arguments
+        let (exit_code, _) = run("FUN f\nPRM 1\nRTV *@0\nEFN\nLOC 1\nSEA @0\nSET 42\nCAL f\n");
+        assert!(exit_code
+            .unwrap_err()
+            .to_string()
+            .contains("Function takes 1 argument(s) but only 0 were passed"));
+
+        let (exit_code, stack_pointer) = run("FUN f\nEFN\nARG 5\nCAL f\nEXT 0\n");
+        assert_eq!(exit_code.unwrap(), 0);
+        assert_eq!(stack_pointer, 1);
+
+        // Popping an argument means it isn't passed
+        let (exit_code, _) = run("FUN f\nPRM 1\nRTV *@0\nEFN
+            ARG 5\nPOP\nPSH 1\nPSH 2\nCAL f\nSEA 0\nPOP\nEXT *0\n");
+        assert_eq!(exit_code.unwrap(), 2);
+
+        let (exit_code, _) = run("FUN f\nPRM 2\nEFN\nLOC 1\nCAL g\nFUN g\nARG 1\nCAL f\nEFN\n");
+        assert!(exit_code
+            .unwrap_err()
+            .to_string()
+            .contains("Function takes 2 argument(s) but only 1 were passed"));
+    }
+
+    #[test]
+    fn jump_does_not_call_functions() {
+        let memory = &mut Memory::default();
+        let program = program(vec![
+            Instruction::Function(Value::Literal(0x01)),
+            Instruction::SetAddress(Value::Literal(0x00)),
+            Instruction::SetValue(Value::Literal(0x07)),
+            Instruction::EndFunction,
+            Instruction::Jump(Value::Literal(0x01)),
+            Instruction::Exit(Value::Address(0x00)),
+        ]);
+
+        assert_eq!(
+            interpret(memory, &program, &mut Buffer::default())
+                .unwrap_err()
+                .to_string(),
+            "error: Use of undeclared label 0x0001 (ADDRESS is 0x0000) at instruction 4"
+        );
+        assert_eq!(memory[0x00], 0x00);
+    }
+
+    #[test]
+    fn functions_are_skipped() {
+        let memory = &mut Memory::default();
+        let program = program(vec![
+            Instruction::SetAddress(Value::Literal(0x00)),
+            Instruction::Function(Value::Literal(0x01)),
+            Instruction::SetValue(Value::Literal(0x01)),
+            Instruction::Compare(Value::Literal(0x01)),
+            Instruction::BranchIfNotEqual(Value::Literal(0x02)),
+            Instruction::Return,
+            Instruction::Label(Value::Literal(0x02)),
+            Instruction::SetValue(Value::Literal(0x02)),
+            Instruction::EndFunction,
+            Instruction::Call(Value::Literal(0x01)),
+            Instruction::Exit(Value::Address(0x00)),
+        ]);
+
+        assert_eq!(
+            interpret(memory, &program, &mut Buffer::default()).unwrap(),
+            0x01
+        );
+    }
+
+    #[test]
+    fn function_without_end() {
+        let memory = &mut Memory::default();
+        let program = program(vec![
+            Instruction::Function(Value::Literal(0x01)),
+            Instruction::Output,
+        ]);
+
+        assert_eq!(
+            interpret(memory, &program, &mut Buffer::default())
+                .unwrap_err()
+                .to_string(),
+            "error: Function has no matching EFN (ADDRESS is 0x0000) at instruction 0"
+        );
+    }
+
+    #[test]
+    fn exit_stops_execution() {
+        let memory = &mut Memory::default();
+        let program = program(vec![
+            Instruction::SetAddress(Value::Literal(0x10)),
+            Instruction::SetValue(Value::Literal(0x03)),
+            Instruction::Exit(Value::Address(0x10)),
+            Instruction::SetValue(Value::Literal(0x04)),
+        ]);
+
+        assert_eq!(
+            interpret(memory, &program, &mut Buffer::default()).unwrap(),
+            0x03
+        );
+        assert_eq!(memory[0x10], 0x03);
+    }
+
+    #[test]
+    fn address_space() {
+        let memory = &mut Memory::default();
+        let last_cell = program(vec![
+            Instruction::SetAddress(Value::Literal(0xFFFF)),
+            Instruction::SetValue(Value::Literal(0x05)),
+            Instruction::SetAddress(Value::Literal(0x10)),
+            Instruction::SetValue(Value::Literal(0x07)),
+            Instruction::Add(Value::Address(0xFFFF)),
+        ]);
+
+        interpret(memory, &last_cell, &mut Buffer::default()).unwrap();
+        assert_eq!(memory[0xFFFF], 0x05);
+        assert_eq!(memory[0x10], 0x0C);
+
+        // Registers are at the same addresses with less than a page of memory
+        let memory = &mut Memory::new(0x1000).unwrap();
+        let registers = program(vec![
+            Instruction::SetAddress(Value::Literal(0x00)),
+            Instruction::SetValue(Value::Literal(0x03)),
+            Instruction::Compare(Value::Literal(0x03)),
+            Instruction::SetValue(Value::Address(0xFFFD)),
+        ]);
+
+        interpret(memory, &registers, &mut Buffer::default()).unwrap();
+        assert_eq!(memory[0x00], 0x01);
+
+        let memory = &mut Memory::new(0x1000).unwrap();
+        let out_of_range = program(vec![
+            Instruction::SetAddress(Value::Literal(0x1000)),
+            Instruction::SetValue(Value::Literal(0x01)),
+        ]);
+
+        assert_eq!(
+            interpret(memory, &out_of_range, &mut Buffer::default())
+                .unwrap_err()
+                .to_string(),
+            "error: Address 0x1000 is outside of memory (ADDRESS is 0x1000) at instruction 1"
+        );
+    }
+
+    #[test]
+    fn pages() {
+        let memory = &mut Memory::new(2 * PAGE_SIZE).unwrap();
+        let program = program(vec![
+            Instruction::SetAddress(Value::Literal(0x10)),
+            Instruction::SetValue(Value::Literal(0x01)),
+            Instruction::Page(Value::Literal(0x01)),
+            Instruction::SetValue(Value::Literal(0x02)),
+            Instruction::Push(Value::Address(0x10)),
+            Instruction::Page(Value::Literal(0x00)),
+            Instruction::SetAddress(Value::Literal(0x11)),
+            Instruction::Pop,
+            Instruction::Page(Value::Literal(0x02)),
+            Instruction::SetValue(Value::Literal(0x03)),
+        ]);
+
+        assert_eq!(
+            interpret(memory, &program, &mut Buffer::default())
+                .unwrap_err()
+                .to_string(),
+            "error: Address 0x20011 is outside of memory (ADDRESS is 0x0011) at instruction 9"
+        );
+        assert_eq!(memory.cells()[0x10..0x12], [0x01, 0x02]);
+        assert_eq!(memory[PAGE_SIZE + 0x10], 0x02);
+    }
+
+    #[test]
+    fn frames_and_registers_ignore_pages() {
+        let memory = &mut Memory::new(2 * PAGE_SIZE).unwrap();
+        let frames = program(vec![
+            Instruction::Function(Value::Literal(0x01)),
+            Instruction::Locals(Value::Literal(0x01)),
+            Instruction::Page(Value::Literal(0x01)),
+            Instruction::SetAddress(Value::Frame(0x00)),
+            Instruction::SetValue(Value::Literal(0x07)),
+            Instruction::Page(Value::Literal(0x00)),
+            Instruction::ReturnValue(Value::FrameAddress(0x00)),
+            Instruction::EndFunction,
+            Instruction::Call(Value::Literal(0x01)),
+            Instruction::Page(Value::Literal(0x01)),
+            Instruction::SetAddress(Value::Literal(0x20)),
+            Instruction::SetAddress(Value::Address(0xFFFE)),
+            Instruction::Pop,
+            Instruction::Exit(Value::Address(0x20)),
+        ]);
+
+        assert_eq!(
+            interpret(memory, &frames, &mut Buffer::default()).unwrap(),
+            0x07
+        );
+        assert_eq!(memory[PAGE_SIZE + 0x20], 0x07);
+    }
+
+    #[test]
+    fn input_and_output() {
+        let memory = &mut Memory::default();
+        let program = program(vec![
+            Instruction::CharacterInput,
+            Instruction::CharacterOutput,
+            Instruction::Subtract(Value::Literal('0' as u16)),
+            Instruction::Multiply(Value::Literal(0x02)),
+            Instruction::Output,
+            Instruction::CharacterInput,
+            Instruction::Add(Value::Literal(0x01)),
+        ]);
+
+        let io = &mut Buffer::new("7");
+        interpret(memory, &program, io).unwrap();
+        assert_eq!(io.output(), "714");
+        assert_eq!(memory[0x00], END_OF_INPUT.wrapping_add(1));
+        assert_eq!(
+            memory.register(Register::Status),
+            END_OF_INPUT_FLAG | CARRY_FLAG | ZERO_FLAG
+        );
+    }
+
+    #[test]
+    fn wide_input() {
+        let memory = &mut Memory::default();
+        let wide = program(vec![
+            Instruction::CharacterInput,
+            Instruction::CharacterInput,
+        ]);
+        assert_eq!(
+            interpret(memory, &wide, &mut Buffer::new("€🦀"))
+                .unwrap_err()
+                .to_string(),
+            "error: Character 🦀 (U+1F980) does not fit in a cell (ADDRESS is 0x0000) at instruction 1"
+        );
+        assert_eq!(memory[0x00], '€' as u16);
+    }
+
+    #[test]
+    fn limits() {
+        let endless = program(vec![
+            Instruction::Label(Value::Literal(0x00)),
+            Instruction::Jump(Value::Literal(0x00)),
+        ]);
+
+        let options = RunOptions {
+            max_steps: Some(10),
+            ..Default::default()
+        };
+        let err = interpret_with_options(
+            &mut Memory::default(),
+            &endless,
+            &mut Buffer::default(),
+            &options,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "error: Step limit of 10 reached (ADDRESS is 0x0000) at instruction 0"
+        );
+
+        let options = RunOptions {
+            timeout: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        let err = interpret_with_options(
+            &mut Memory::default(),
+            &endless,
+            &mut Buffer::default(),
+            &options,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Runtime(RuntimeError {
+                fault: Fault::Timeout(_),
+                ..
+            })
+        ));
+
+        let options = RunOptions {
+            max_steps: Some(3),
+            timeout: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let finishes = program(vec![
+            Instruction::SetAddress(Value::Literal(0x00)),
+            Instruction::SetValue(Value::Literal(0x01)),
+            Instruction::Exit(Value::Address(0x00)),
+        ]);
+        assert_eq!(
+            interpret_with_options(
+                &mut Memory::default(),
+                &finishes,
+                &mut Buffer::default(),
+                &options
+            )
+            .unwrap(),
+            0x01
+        );
+    }
+
+    #[test]
+    fn trace() {
+        let program = lex_source(SourceFile {
+            path: "test.jasm".to_string(),
+            text:
+                "SEA 0x10\nSET 0x05\nFUN 0x01\nPRM 0x01\nRTV *@0\nEFN\nARG *0x10\nCAL 0x01\nPOP\n"
+                    .to_string(),
+        })
+        .unwrap();
+
+        let options = RunOptions {
+            trace: Some(TraceFormat::Text),
+            ..Default::default()
+        };
+        let io = &mut Buffer::default();
+        interpret_with_options(&mut Memory::default(), &program, io, &options).unwrap();
+
+        let lines: Vec<&str> = io.traced().lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "[0] test.jasm:1:1 SEA 0x0010 = 0x0010  ADDRESS 0x0000  [0xfffe] 0x0000 -> 0x0010  depth 0",
+                "[1] test.jasm:2:1 SET 0x0005 = 0x0005  ADDRESS 0x0010  [0x0010] 0x0000 -> 0x0005  depth 0",
+                "[2] test.jasm:3:1 FUN 0x0001 = 0x0001  ADDRESS 0x0010  depth 0",
+                "[6] test.jasm:7:1 ARG *0x0010 = 0x0005  ADDRESS 0x0010  [0xfff9] 0x0000 -> 0x0005  [0xfffb] 0x0000 -> 0x0001  depth 0",
+                "[7] test.jasm:8:1 CAL 0x0001 = 0x0001  ADDRESS 0x0010  [0xfffa] 0x0000 -> 0x0001  depth 0",
+                "[3] test.jasm:4:1 PRM 0x0001 = 0x0001  ADDRESS 0x0010  [0xfffa] 0x0001 -> 0x0000  depth 1",
+                "[4] test.jasm:5:1 RTV *@0 = 0x0005  ADDRESS 0x0010  depth 1",
+                "[8] test.jasm:9:1 POP  ADDRESS 0x0010  [0xfffb] 0x0001 -> 0x0000  depth 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn data_is_loaded_before_execution() {
+        let memory = &mut Memory::default();
+        let program = Program {
+            instructions: vec![
+                Instruction::SetAddress(Value::Literal(0x101)),
+                Instruction::Exit(Value::Address(0x101)),
+            ],
+            data: vec![DataBlock {
+                address: 0x100,
+                words: vec![0x01, 0x02],
+            }],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            interpret(memory, &program, &mut Buffer::default()).unwrap(),
+            0x02
+        );
+    }
 }
@@ -0,0 +1,178 @@
+use std::{
+    collections::VecDeque,
+    io::{self, IsTerminal, Read, Write},
+};
+
+/// Where a running program's output goes and its input comes from.
+pub trait Io {
+    /// Writes output from `OUT`, `CUT` or `DMP`.
+    fn write(&mut self, text: &str) -> io::Result<()>;
+
+    /// Reads a character for `CIN`, or `None` if there is no input left.
+    fn read_char(&mut self) -> io::Result<Option<char>>;
+
+    /// Writes a line of a trace, which goes to stderr unless overridden.
+    fn trace(&mut self, line: &str) -> io::Result<()> {
+        writeln!(io::stderr(), "{}", line)
+    }
+}
+
+/// Reads from stdin and writes to stdout. Input is read a key at a time from a
+/// terminal, or a character at a time when stdin is piped or redirected.
+pub struct Stdio {
+    term: console::Term,
+    /// Whether to write each character read back out, as a terminal would.
+    echo: bool,
+}
+
+impl Stdio {
+    pub fn new(echo: bool) -> Self {
+        Self {
+            term: console::Term::stdout(),
+            echo,
+        }
+    }
+}
+
+impl Default for Stdio {
+    fn default() -> Self {
+        Self::new(true)
+    }
+}
+
+impl Io for Stdio {
+    fn write(&mut self, text: &str) -> io::Result<()> {
+        print!("{}", text);
+        io::stdout().flush()
+    }
+
+    fn read_char(&mut self) -> io::Result<Option<char>> {
+        let char = if io::stdin().is_terminal() {
+            Some(self.term.read_char()?)
+        } else {
+            read_utf8(&mut io::stdin().lock())?
+        };
+
+        if let (Some(char), true) = (char, self.echo) {
+            self.write(&char.to_string())?;
+        }
+
+        Ok(char)
+    }
+}
+
+/// Reads from and writes to memory, for capturing a program's output.
+#[derive(Debug, Default)]
+pub struct Buffer {
+    input: VecDeque<char>,
+    output: String,
+    trace: String,
+}
+
+impl Buffer {
+    pub fn new(input: &str) -> Self {
+        Self {
+            input: input.chars().collect(),
+            output: String::new(),
+            trace: String::new(),
+        }
+    }
+
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    pub fn traced(&self) -> &str {
+        &self.trace
+    }
+}
+
+impl Io for Buffer {
+    fn write(&mut self, text: &str) -> io::Result<()> {
+        self.output.push_str(text);
+        Ok(())
+    }
+
+    fn read_char(&mut self) -> io::Result<Option<char>> {
+        Ok(self.input.pop_front())
+    }
+
+    fn trace(&mut self, line: &str) -> io::Result<()> {
+        self.trace.push_str(line);
+        self.trace.push('\n');
+        Ok(())
+    }
+}
+
+/// Answers input from a script and writes to stdout, echoing each answer as
+/// if it had been typed.
+pub struct Scripted {
+    script: VecDeque<char>,
+}
+
+impl Scripted {
+    pub fn new(script: &str) -> Self {
+        Self {
+            script: script.chars().collect(),
+        }
+    }
+}
+
+impl Io for Scripted {
+    fn write(&mut self, text: &str) -> io::Result<()> {
+        print!("{}", text);
+        io::stdout().flush()
+    }
+
+    fn read_char(&mut self) -> io::Result<Option<char>> {
+        let char = self.script.pop_front();
+        if let Some(char) = char {
+            self.write(&char.to_string())?;
+        }
+
+        Ok(char)
+    }
+}
+
+/// Reads one UTF-8 encoded character, or `None` at the end of the input.
+fn read_utf8(reader: &mut impl Read) -> io::Result<Option<char>> {
+    let mut bytes = [0; 4];
+    if reader.read(&mut bytes[..1])? == 0 {
+        return Ok(None);
+    }
+
+    let length = match bytes[0] {
+        0x00..=0x7F => 1,
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => 0,
+    };
+
+    if length > 1 {
+        reader.read_exact(&mut bytes[1..length])?;
+    }
+
+    std::str::from_utf8(&bytes[..length])
+        .ok()
+        .and_then(|text| text.chars().next())
+        .map(Some)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Input is not valid UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utf8_input() {
+        let mut input = "a£€🦀".as_bytes();
+        for expected in "a£€🦀".chars() {
+            assert_eq!(read_utf8(&mut input).unwrap(), Some(expected));
+        }
+        assert_eq!(read_utf8(&mut input).unwrap(), None);
+
+        assert!(read_utf8(&mut [0xFF].as_slice()).is_err());
+        assert!(read_utf8(&mut [0xE2, 0x82].as_slice()).is_err());
+    }
+}
@@ -1,108 +1,424 @@
-use crate::Value;
-use anyhow::{anyhow, Result};
-use std::{
-    fs::File,
-    io::{self, BufRead, Read},
-    path::Path,
+use std::{fs, num::ParseIntError, str::Chars, sync::Arc};
+
+use crate::{
+    compiler::DATA_RECORD,
+    diagnostic::{Diagnostic, Diagnostics, SourceFile, Span},
+    error::{DecodeError, Error, LiteralError},
+    instructions::Instruction,
+    parser::parse,
+    program::{DataBlock, Program},
+    PAGE_SIZE,
 };
 
-use crate::instructions::Instruction;
-
-pub fn lex_str(path: &String) -> Result<Vec<Instruction>> {
-    let mut instructions: Vec<Instruction> = Vec::new();
-    let lines = read_lines(path)?;
-    for line in lines {
-        let mut found_comment = false;
-        let line: String = line?
-            .trim_start()
-            .chars()
-            .filter(|c| {
-                if c == &';' {
-                    found_comment = true;
-                }
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Identifier(String),
+    Number(u16),
+    String(String),
+    Star,
+    At,
+}
 
-                !found_comment
-            })
-            .collect();
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
 
-        if line.trim().is_empty() {
-            continue;
-        }
+pub fn lex_str(path: &String) -> Result<Program, Error> {
+    lex_source(SourceFile {
+        path: path.clone(),
+        text: fs::read_to_string(path)?,
+    })
+}
 
-        let mut tokens: Vec<&str> = line.split_whitespace().collect();
-        let instruction: &str;
-        let mut value: Option<Value> = None;
+pub fn lex_source(file: SourceFile) -> Result<Program, Error> {
+    Ok(parse(Arc::new(file))?)
+}
 
-        if tokens.len() == 0 {
-            return Err(anyhow!("No tokens found"));
+/// Splits a source file into lines of tokens, dropping comments and blank
+/// lines.
+pub fn tokenize(file: &Arc<SourceFile>) -> Result<Vec<Vec<Token>>, Diagnostics> {
+    let mut lines: Vec<Vec<Token>> = Vec::new();
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+
+    for (i, text) in file.text.lines().enumerate() {
+        match tokenize_line(file, i + 1, text) {
+            Ok(tokens) if tokens.is_empty() => {}
+            Ok(tokens) => lines.push(tokens),
+            Err(diagnostic) => diagnostics.push(diagnostic),
         }
+    }
 
-        instruction = tokens[0];
+    if diagnostics.is_empty() {
+        Ok(lines)
+    } else {
+        Err(Diagnostics(diagnostics))
+    }
+}
+
+fn tokenize_line(
+    file: &Arc<SourceFile>,
+    line: usize,
+    text: &str,
+) -> Result<Vec<Token>, Diagnostic> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut i = 0;
 
-        if tokens.len() > 1 {
-            let is_address = tokens[1].starts_with("*");
-            tokens[1] = tokens[1].trim_start_matches('*');
+    let span = |start: usize, end: usize| Span {
+        file: file.clone(),
+        line,
+        column: start + 1,
+        length: end.min(chars.len()) - start,
+    };
 
-            if tokens[1].starts_with("0x") {
-                let hex = u16::from_str_radix(tokens[1].trim_start_matches("0x"), 16)?;
-                value = Some(if is_address {
-                    Value::Address(hex)
+    while i < chars.len() {
+        let start = i;
+        let kind = match chars[i] {
+            ';' => break,
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '*' => {
+                i += 1;
+                TokenKind::Star
+            }
+            '@' => {
+                i += 1;
+                TokenKind::At
+            }
+            quote @ ('\'' | '"') => {
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        Some('\\') => i += 2,
+                        Some(&c) if c == quote => break,
+                        Some(_) => i += 1,
+                        None => {
+                            return Err(Diagnostic::new(
+                                if quote == '"' {
+                                    "Unterminated string literal"
+                                } else {
+                                    "Unterminated character literal"
+                                },
+                                &span(start, i),
+                            ))
+                        }
+                    }
+                }
+                i += 1;
+
+                let lexeme: String = chars[start..i].iter().collect();
+                let kind = if quote == '"' {
+                    parse_string_literal(&lexeme).map(TokenKind::String)
                 } else {
-                    Value::Literal(hex)
-                });
-            } else if tokens[1].starts_with("0b") {
-                let bin = u16::from_str_radix(tokens[1].trim_start_matches("0b"), 2)?;
-                value = Some(if is_address {
-                    Value::Address(bin)
+                    parse_char_literal(&lexeme).map(TokenKind::Number)
+                };
+
+                kind.map_err(|err| err.diagnostic(&span(start, i)))?
+            }
+            c if c.is_ascii_alphanumeric() || c == '_' || c == '-' => {
+                i += 1;
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+
+                let lexeme: String = chars[start..i].iter().collect();
+                if c.is_ascii_alphabetic() || c == '_' {
+                    TokenKind::Identifier(lexeme)
                 } else {
-                    Value::Literal(bin)
-                });
-            } else {
-                return Err(anyhow!("Unknown number literal {}", tokens[1]));
+                    let value =
+                        parse_literal(&lexeme).map_err(|err| err.diagnostic(&span(start, i)))?;
+                    TokenKind::Number(value)
+                }
+            }
+            c => {
+                return Err(Diagnostic::new(
+                    format!("Unexpected character {}", c),
+                    &span(start, start + 1),
+                ))
             }
+        };
+
+        tokens.push(Token {
+            kind,
+            span: span(start, i),
+        });
+    }
+
+    Ok(tokens)
+}
+
+/// Parses a hex (`0x`), binary (`0b`), decimal or character literal. Negative
+/// decimals are stored as two's complement.
+fn parse_literal(token: &str) -> Result<u16, LiteralError> {
+    if let Some(hex) = token.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16).map_err(|err| invalid_literal(token, err))
+    } else if let Some(bin) = token.strip_prefix("0b") {
+        u16::from_str_radix(bin, 2).map_err(|err| invalid_literal(token, err))
+    } else if token.starts_with('\'') {
+        parse_char_literal(token)
+    } else if let Some(negative) = token.strip_prefix('-') {
+        if !negative.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(LiteralError::UnknownNumber(token.to_string()));
         }
 
-        instructions.push(Instruction::from_string(instruction, value)?);
+        let value: i32 = token.parse().map_err(|err| invalid_literal(token, err))?;
+        if value < i16::MIN as i32 {
+            return Err(LiteralError::NumberOutOfRange(token.to_string()));
+        }
+
+        Ok(value as i16 as u16)
+    } else if token.starts_with(|c: char| c.is_ascii_digit()) {
+        token
+            .parse::<u16>()
+            .map_err(|err| invalid_literal(token, err))
+    } else {
+        Err(LiteralError::UnknownNumber(token.to_string()))
+    }
+}
+
+fn invalid_literal(token: &str, err: ParseIntError) -> LiteralError {
+    LiteralError::InvalidNumber(token.to_string(), err)
+}
+
+fn parse_char_literal(token: &str) -> Result<u16, LiteralError> {
+    let inner = token
+        .strip_prefix('\'')
+        .and_then(|token| token.strip_suffix('\''))
+        .ok_or_else(|| LiteralError::InvalidCharacter(token.to_string()))?;
+
+    let mut chars = inner.chars();
+    let c = match chars.next() {
+        Some('\\') => unescape(&mut chars, token)?,
+        Some(c) => c,
+        None => return Err(LiteralError::EmptyCharacter(token.to_string())),
+    };
+
+    if chars.next().is_some() {
+        return Err(LiteralError::MultipleCharacters(token.to_string()));
     }
 
-    Ok(instructions)
+    u16::try_from(c as u32).map_err(|_| LiteralError::WideCharacter(token.to_string()))
 }
 
-pub fn lex_bin(path: &String) -> Result<Vec<Instruction>> {
-    let mut instructions: Vec<Instruction> = Vec::new();
-    let file = File::open(path)?;
-    let mut reader = io::BufReader::new(file);
+fn parse_string_literal(token: &str) -> Result<String, LiteralError> {
+    let inner = token
+        .strip_prefix('"')
+        .and_then(|token| token.strip_suffix('"'))
+        .ok_or_else(|| LiteralError::InvalidString(token.to_string()))?;
 
-    let mut buffer = [0; 2];
-    loop {
-        let n = reader.read(&mut buffer)?;
-        if n == 0 {
-            break;
+    let mut string = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        string.push(match c {
+            '\\' => unescape(&mut chars, token)?,
+            c => c,
+        });
+    }
+
+    Ok(string)
+}
+
+/// Decodes the escape sequence following a backslash in `token`.
+fn unescape(chars: &mut Chars, token: &str) -> Result<char, LiteralError> {
+    Ok(match chars.next() {
+        Some('n') => '\n',
+        Some('r') => '\r',
+        Some('t') => '\t',
+        Some('0') => '\0',
+        Some('\\') => '\\',
+        Some('\'') => '\'',
+        Some('"') => '"',
+        _ => return Err(LiteralError::UnknownEscape(token.to_string())),
+    })
+}
+
+pub fn lex_bin(path: &String) -> Result<Program, Error> {
+    Ok(decode(&fs::read(path)?)?)
+}
+
+/// Decodes the contents of a `.jasmb` file.
+pub fn decode(bytes: &[u8]) -> Result<Program, DecodeError> {
+    let mut program = Program::default();
+    let mut offset = 0;
+
+    while offset < bytes.len() {
+        let instruction_buf: [u8; 2] = read_pair(bytes, offset)?;
+
+        if instruction_buf[0] == DATA_RECORD {
+            let address = u16::from_be_bytes(read_pair(bytes, offset + 2)?);
+            let length = u16::from_be_bytes(read_pair(bytes, offset + 4)?) as usize;
+            if address as usize + length > PAGE_SIZE {
+                return Err(DecodeError::DataOutOfMemory(offset));
+            }
+
+            let words = (0..length)
+                .map(|i| read_pair(bytes, offset + 6 + i * 2).map(u16::from_be_bytes))
+                .collect::<Result<Vec<u16>, DecodeError>>()?;
+
+            program.data.push(DataBlock { address, words });
+            offset += 6 + length * 2;
+            continue;
         }
 
         let mut value: Option<u16> = None;
-        let mut instruction_buf = buffer;
-        if Instruction::u8_requires_value(buffer[1]) {
-            instruction_buf = buffer.clone();
-            let n = reader.read(&mut buffer)?;
-            if n != 2 {
-                return Err(anyhow!("Unexpected end of file"));
-            }
 
-            value = Some(u16::from_be_bytes(buffer));
+        if Instruction::u8_requires_value(instruction_buf[1]) {
+            value = Some(u16::from_be_bytes(read_pair(bytes, offset + 2)?));
         }
-        let instruction = Instruction::from_u8(instruction_buf, value)?;
 
-        instructions.push(instruction);
+        let instruction = Instruction::from_u8(instruction_buf, value)
+            .map_err(|err| DecodeError::InvalidInstruction(offset, err))?;
+        offset += if value.is_some() { 4 } else { 2 };
+
+        program.instructions.push(instruction);
     }
 
-    Ok(instructions)
+    Ok(program)
+}
+
+fn read_pair(bytes: &[u8], offset: usize) -> Result<[u8; 2], DecodeError> {
+    bytes
+        .get(offset..offset + 2)
+        .map(|pair| [pair[0], pair[1]])
+        .ok_or(DecodeError::UnexpectedEnd(bytes.len()))
 }
 
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
-where
-    P: AsRef<Path>,
-{
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions::InstructionError;
+
+    #[test]
+    fn number_literals() {
+        assert_eq!(parse_literal("0x2A").unwrap(), 42);
+        assert_eq!(parse_literal("0b101010").unwrap(), 42);
+        assert_eq!(parse_literal("42").unwrap(), 42);
+        assert_eq!(parse_literal("65535").unwrap(), 0xFFFF);
+        assert!(parse_literal("65536").is_err());
+        assert!(parse_literal("4x").is_err());
+    }
+
+    #[test]
+    fn negative_literals() {
+        assert_eq!(parse_literal("-1").unwrap(), 0xFFFF);
+        assert_eq!(parse_literal("-32768").unwrap(), 0x8000);
+        assert_eq!(
+            parse_literal("-32769"),
+            Err(LiteralError::NumberOutOfRange("-32769".to_string()))
+        );
+        assert!(parse_literal("-").is_err());
+        assert!(parse_literal("-a").is_err());
+    }
+
+    #[test]
+    fn character_literals() {
+        assert_eq!(parse_literal("'A'").unwrap(), 0x41);
+        assert_eq!(parse_literal("'\\n'").unwrap(), 0x0A);
+        assert_eq!(parse_literal("'\\''").unwrap(), 0x27);
+        assert_eq!(parse_literal("'\\\\'").unwrap(), 0x5C);
+        assert!(parse_literal("''").is_err());
+        assert!(parse_literal("'AB'").is_err());
+        assert_eq!(
+            parse_literal("'\\q'"),
+            Err(LiteralError::UnknownEscape("'\\q'".to_string()))
+        );
+    }
+
+    fn kinds(text: &str) -> Vec<Vec<TokenKind>> {
+        let file = Arc::new(SourceFile {
+            path: "test.jasm".to_string(),
+            text: text.to_string(),
+        });
+
+        tokenize(&file)
+            .unwrap()
+            .into_iter()
+            .map(|line| line.into_iter().map(|token| token.kind).collect())
+            .collect()
+    }
+
+    #[test]
+    fn tokenize_lines() {
+        assert_eq!(
+            kinds("SEA *0x10 ; comment\n\n  ; only a comment\nJMP loop_start"),
+            vec![
+                vec![
+                    TokenKind::Identifier("SEA".to_string()),
+                    TokenKind::Star,
+                    TokenKind::Number(0x10),
+                ],
+                vec![
+                    TokenKind::Identifier("JMP".to_string()),
+                    TokenKind::Identifier("loop_start".to_string()),
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_character_literals() {
+        assert_eq!(
+            kinds("SET ' '\nSET ';'\nSET '\\''"),
+            vec![
+                vec![
+                    TokenKind::Identifier("SET".to_string()),
+                    TokenKind::Number(0x20)
+                ],
+                vec![
+                    TokenKind::Identifier("SET".to_string()),
+                    TokenKind::Number(0x3B)
+                ],
+                vec![
+                    TokenKind::Identifier("SET".to_string()),
+                    TokenKind::Number(0x27)
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert_eq!(
+            decode(&[0x00, 0x05, 0x00]).unwrap_err(),
+            DecodeError::UnexpectedEnd(3)
+        );
+        assert_eq!(
+            decode(&[0x00, 0x00, 0x00, 0xFF, 0x00, 0x00]).unwrap_err(),
+            DecodeError::InvalidInstruction(2, InstructionError::UnknownOpcode(0xFF))
+        );
+        assert_eq!(
+            decode(&[
+                DATA_RECORD,
+                0x00,
+                0xFF,
+                0xFF,
+                0x00,
+                0x02,
+                0x00,
+                0x00,
+                0x00,
+                0x00
+            ])
+            .unwrap_err(),
+            DecodeError::DataOutOfMemory(0)
+        );
+    }
+
+    #[test]
+    fn tokenize_spans() {
+        let file = Arc::new(SourceFile {
+            path: "test.jasm".to_string(),
+            text: "SEA 0x10\n\tSET 0xZZ".to_string(),
+        });
+
+        let diagnostics = tokenize(&file).unwrap_err();
+        assert_eq!(diagnostics.0.len(), 1);
+
+        let span = diagnostics.0[0].span.as_ref().unwrap();
+        assert_eq!((span.line, span.column, span.length), (2, 6, 4));
+    }
 }
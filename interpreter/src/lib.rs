@@ -0,0 +1,35 @@
+pub mod checker;
+pub mod compiler;
+pub mod diagnostic;
+pub mod error;
+pub mod instructions;
+pub mod interpreter;
+pub mod io;
+pub mod lexer;
+pub mod memory;
+pub mod parser;
+pub mod program;
+pub mod trace;
+
+pub use error::Error;
+pub use memory::Memory;
+
+pub const STACK_SIZE: usize = 256;
+/// The number of words an address can reach. Memory past the first page is
+/// reached by selecting a page with `PAG`.
+pub const PAGE_SIZE: usize = 65536;
+pub const MEMORY_SIZE: usize = PAGE_SIZE;
+pub const MIN_MEMORY_SIZE: usize = 1024;
+pub const MAX_MEMORY_SIZE: usize = 256 * PAGE_SIZE;
+
+/// Bits of the status register, which every arithmetic instruction sets from
+/// its result. Carry is set on unsigned overflow (or borrow, for
+/// `SUB`), and overflow on signed overflow.
+pub const CARRY_FLAG: u16 = 0b0001;
+pub const OVERFLOW_FLAG: u16 = 0b0010;
+pub const ZERO_FLAG: u16 = 0b0100;
+pub const NEGATIVE_FLAG: u16 = 0b1000;
+/// Set by `CIN` once there is no input left to read, and kept set from then
+/// on. The cell `CIN` was reading into is set to `END_OF_INPUT`.
+pub const END_OF_INPUT_FLAG: u16 = 0b10000;
+pub const END_OF_INPUT: u16 = 0xFFFF;
@@ -1,17 +1,24 @@
-use std::{fs::File, io::Write, time::Instant};
+use std::{
+    fs::File,
+    io::Write,
+    process,
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, Result};
 use clap::{Parser, Subcommand};
 
-mod compiler;
-mod instructions;
-mod interpreter;
-mod lexer;
-use crate::{
+use jasm::{
+    checker::check,
     compiler::compile,
-    instructions::Value,
-    interpreter::interpret,
+    diagnostic::{Diagnostics, Severity},
+    error::{Fault, RuntimeError},
+    interpreter::{interpret_with_options, RunOptions},
+    io::Stdio,
     lexer::{lex_bin, lex_str},
+    program::Program,
+    trace::TraceFormat,
+    Error, Memory, MEMORY_SIZE,
 };
 
 #[derive(Parser)]
@@ -26,17 +33,81 @@ pub struct Args {
 
 #[derive(Subcommand)]
 enum Commands {
-    Run { path: String },
-    Compile { path: String },
+    #[command(after_help = RUN_EXIT_STATUSES)]
+    Run {
+        path: String,
+        /// The number of words of memory to run the program with. The stack and
+        /// registers at the top of the first page are there even with less than
+        /// a page
+        #[arg(long, default_value_t = MEMORY_SIZE)]
+        memory: usize,
+        /// Don't write characters read by CIN back out
+        #[arg(long)]
+        no_echo: bool,
+        /// Stop the program after it has run this many instructions
+        #[arg(long)]
+        max_steps: Option<u64>,
+        /// Stop the program after it has run for this many seconds
+        #[arg(long, value_parser = parse_seconds)]
+        timeout: Option<Duration>,
+        /// Write each instruction run to stderr, as text or json
+        #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "text")]
+        trace: Option<TraceFormat>,
+    },
+    Compile {
+        path: String,
+    },
 }
 
-pub const ADDRESS_ADDRESS: usize = 65534;
-pub const COMPARISON_ADDRESS: usize = 65533;
-pub type Memory = [u16; 65535];
+/// The highest exit code a program can exit with. Codes above it are reserved
+/// for jasm's own failures, so they can always be told apart.
+const MAX_PROGRAM_EXIT_CODE: u16 = 239;
+/// Bad arguments, or a program that could not be loaded or failed its checks.
+const ERROR_EXIT_CODE: i32 = 240;
+const FAULT_EXIT_CODE: i32 = 241;
+const STEP_LIMIT_EXIT_CODE: i32 = 242;
+const TIMEOUT_EXIT_CODE: i32 = 243;
+
+const RUN_EXIT_STATUSES: &str = "Exit status:
+  0-239  The program's exit code. Codes above 239 exit with 239
+  240    The arguments were invalid, or the program could not be loaded
+  241    The program faulted
+  242    The program reached --max-steps
+  243    The program reached --timeout";
+
+fn main() {
+    let args = Args::try_parse().unwrap_or_else(|err| {
+        if !err.use_stderr() {
+            err.exit();
+        }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+        let _ = err.print();
+        process::exit(ERROR_EXIT_CODE);
+    });
+
+    if let Err(err) = run(args) {
+        let Some(err) = err.downcast_ref::<Error>() else {
+            eprintln!("error: {:#}", err);
+            process::exit(ERROR_EXIT_CODE);
+        };
+
+        eprintln!("{}", err);
+        process::exit(match err {
+            Error::Runtime(RuntimeError {
+                fault: Fault::StepLimit(_),
+                ..
+            }) => STEP_LIMIT_EXIT_CODE,
+            Error::Runtime(RuntimeError {
+                fault: Fault::Timeout(_),
+                ..
+            }) => TIMEOUT_EXIT_CODE,
+            Error::Runtime(_) => FAULT_EXIT_CODE,
+            _ => ERROR_EXIT_CODE,
+        });
+    }
+}
 
+fn run(args: Args) -> Result<()> {
     println!("--- JASM");
 
     let res = match args.command {
@@ -48,7 +119,8 @@ fn main() -> Result<()> {
             println!("--- Compiling file");
             let before_lex = Instant::now();
             let res = lex_str(&path)?;
-            let buf = compile(res)?;
+            check_program(&res)?;
+            let buf = compile(res);
             println!(
                 "--- Compiled in {}ms ({} microseconds)",
                 before_lex.elapsed().as_millis(),
@@ -56,38 +128,53 @@ fn main() -> Result<()> {
             );
 
             println!("--- Writing to File");
-            let mut file = File::create(&path.replace(".jasm", ".jasmb"))?;
-            file.write(&buf)?;
+            let mut file = File::create(path.replace(".jasm", ".jasmb"))?;
+            file.write_all(&buf)?;
             println!("--- Compiled Successfully");
             Ok(())
         }
-        Some(Commands::Run { path }) => {
+        Some(Commands::Run {
+            path,
+            memory,
+            no_echo,
+            max_steps,
+            timeout,
+            trace,
+        }) => {
             println!("--- Lexing file");
             let before_lex = Instant::now();
 
-            let mut res = if path.ends_with(".jasm") {
-                lex_str(&path)
+            let program = if path.ends_with(".jasm") {
+                lex_str(&path)?
             } else if path.ends_with(".jasmb") {
-                lex_bin(&path)
+                lex_bin(&path)?
             } else {
-                Err(anyhow!("Invalid file extension"))
-            }?;
+                return Err(anyhow!("Invalid file extension"));
+            };
 
             println!(
                 "--- Lexed {} Instructions in {}ms ({} microseconds)",
-                res.len(),
+                program.instructions.len(),
                 before_lex.elapsed().as_millis(),
                 before_lex.elapsed().as_micros(),
             );
 
+            check_program(&program)?;
+
             println!("--- Interpreting Instructions");
             println!("--- Program Output Begins Here");
 
+            let memory = &mut Memory::new(memory)?;
             let before_interpret = Instant::now();
-            let memory: &mut Memory = &mut [0u16; 65535];
-            let exit_code = interpret(memory, &mut res)?;
-
-            println!("");
+            let options = RunOptions {
+                max_steps,
+                timeout,
+                trace,
+            };
+            let exit_code =
+                interpret_with_options(memory, &program, &mut Stdio::new(!no_echo), &options)?;
+
+            println!();
             println!("Program exited with code {}", exit_code);
             println!(
                 "--- Interpretation finished in {}ms ({} microseconds)",
@@ -95,10 +182,55 @@ fn main() -> Result<()> {
                 before_interpret.elapsed().as_micros(),
             );
 
-            Ok(())
+            process::exit(exit_status(exit_code))
         }
         None => Err(anyhow!("No subcommand specified")),
     };
 
     res
 }
+
+/// Prints any warnings about the program, failing if it has errors.
+fn check_program(program: &Program) -> Result<()> {
+    let diagnostics = check(program);
+
+    if diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.severity == Severity::Error)
+    {
+        return Err(Error::Check(Diagnostics(diagnostics)).into());
+    }
+
+    for warning in diagnostics {
+        eprintln!("{}\n", warning);
+    }
+
+    Ok(())
+}
+
+/// The process exit status for a program's exit code. Codes too high to be
+/// told apart from jasm's own failures exit with `MAX_PROGRAM_EXIT_CODE`
+/// rather than wrapping round to something else, possibly 0.
+fn exit_status(code: u16) -> i32 {
+    code.min(MAX_PROGRAM_EXIT_CODE).into()
+}
+
+fn parse_seconds(seconds: &str) -> Result<Duration, String> {
+    let seconds = seconds.parse::<f64>().map_err(|err| err.to_string())?;
+    Duration::try_from_secs_f64(seconds).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_statuses() {
+        assert_eq!(exit_status(0), 0);
+        assert_eq!(exit_status(3), 3);
+        assert_eq!(exit_status(239), 239);
+        assert_eq!(exit_status(240), 239);
+        assert_eq!(exit_status(256), 239);
+        assert_eq!(exit_status(0xFFFF), 239);
+    }
+}
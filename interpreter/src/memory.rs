@@ -0,0 +1,337 @@
+use std::ops::{Index, IndexMut};
+
+use crate::{
+    error::{Error, Fault},
+    program::DataBlock,
+    trace::Change,
+    MAX_MEMORY_SIZE, MEMORY_SIZE, MIN_MEMORY_SIZE, PAGE_SIZE, STACK_SIZE,
+};
+
+/// Cells the VM keeps its own state in. They sit just below the last cell of
+/// the first page, with the data stack below them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Register {
+    /// The address of the current cell, set by `SEA`.
+    Address,
+    /// The result of the last comparison.
+    Comparison,
+    /// The flags set by the last arithmetic instruction.
+    Status,
+    /// The number of values on the data stack.
+    StackPointer,
+    /// The stack depth at the base of the current function's frame. Frame slot
+    /// `@n` is the `n`th value on the stack above it.
+    FramePointer,
+}
+
+impl Register {
+    /// The cell the register is kept in.
+    pub fn address(self) -> usize {
+        match self {
+            Register::Address => 0xFFFE,
+            Register::Comparison => 0xFFFD,
+            Register::Status => 0xFFFC,
+            Register::StackPointer => 0xFFFB,
+            Register::FramePointer => 0xFFFA,
+        }
+    }
+}
+
+/// The address of the bottom of the stack, just below the registers.
+const STACK_BOTTOM: usize = 0xFFF9;
+
+/// The first of the cells at the top of the first page that hold the stack and
+/// registers. They exist however small memory is, and addresses in this range
+/// refer to them whichever page is selected.
+const RESERVED: usize = STACK_BOTTOM + 1 - STACK_SIZE;
+
+/// The VM's memory, in pages of `PAGE_SIZE` words. Addresses in instructions
+/// refer to the page selected by `PAG`, except those at the top of a page,
+/// which always refer to the registers, the stack and frame slots in the first
+/// page.
+#[derive(Debug, Clone)]
+pub struct Memory {
+    /// At least a page of cells, even when memory is smaller, so that the
+    /// stack and registers are always at the same addresses.
+    cells: Vec<u16>,
+    /// How many words of memory there are.
+    size: usize,
+    /// The page that addresses refer to.
+    page: u16,
+    /// Whether to keep track of writes, for tracing.
+    recording: bool,
+    /// Each cell written to since changes were last taken, in the order they
+    /// were first written, and what it held before.
+    written: Vec<(usize, u16)>,
+}
+
+impl Memory {
+    pub fn new(size: usize) -> Result<Self, Error> {
+        if !(MIN_MEMORY_SIZE..=MAX_MEMORY_SIZE).contains(&size) {
+            return Err(Error::InvalidMemorySize(size));
+        }
+
+        Ok(Self {
+            cells: vec![0; size.max(PAGE_SIZE)],
+            size,
+            page: 0,
+            recording: false,
+            written: Vec::new(),
+        })
+    }
+
+    pub fn cells(&self) -> &[u16] {
+        &self.cells
+    }
+
+    pub fn register(&self, register: Register) -> u16 {
+        self.cells[register.address()]
+    }
+
+    pub fn set_register(&mut self, register: Register, value: u16) {
+        self.set(register.address(), value);
+    }
+
+    pub fn page(&self) -> u16 {
+        self.page
+    }
+
+    pub fn set_page(&mut self, page: u16) {
+        self.page = page;
+    }
+
+    /// Whether `cell` exists, which it does if it is within the size of
+    /// memory or is one of the reserved cells at the top of the first page.
+    fn contains(&self, cell: usize) -> bool {
+        cell < self.size || (RESERVED..PAGE_SIZE).contains(&cell)
+    }
+
+    /// Finds the cell `address` refers to in the current page, or in the first
+    /// page for the reserved cells.
+    fn resolve(&self, address: u16) -> Result<usize, Fault> {
+        if (RESERVED..PAGE_SIZE).contains(&(address as usize)) {
+            return Ok(address as usize);
+        }
+
+        let cell = self.page as usize * PAGE_SIZE + address as usize;
+        if !self.contains(cell) {
+            return Err(Fault::AddressOutOfRange(cell));
+        }
+
+        Ok(cell)
+    }
+
+    pub fn read(&self, address: u16) -> Result<u16, Fault> {
+        Ok(self.cells[self.resolve(address)?])
+    }
+
+    pub fn write(&mut self, address: u16, value: u16) -> Result<(), Fault> {
+        let cell = self.resolve(address)?;
+        self.set(cell, value);
+        Ok(())
+    }
+
+    fn set(&mut self, cell: usize, value: u16) {
+        if self.recording && !self.written.iter().any(|(written, _)| *written == cell) {
+            self.written.push((cell, self.cells[cell]));
+        }
+
+        self.cells[cell] = value;
+    }
+
+    /// Starts or stops keeping track of writes for `take_changes`.
+    pub fn set_recording(&mut self, recording: bool) {
+        self.recording = recording;
+        self.written.clear();
+    }
+
+    /// Returns every cell whose value changed since this was last called,
+    /// registers included. Cells written with the value they already held are
+    /// left out.
+    pub fn take_changes(&mut self) -> Vec<Change> {
+        self.written
+            .drain(..)
+            .map(|(cell, old)| Change {
+                cell,
+                old,
+                new: self.cells[cell],
+            })
+            .filter(|change| change.old != change.new)
+            .collect()
+    }
+
+    /// Reads the cell the address register points at.
+    pub fn current(&self) -> Result<u16, Fault> {
+        self.read(self.register(Register::Address))
+    }
+
+    pub fn set_current(&mut self, value: u16) -> Result<(), Fault> {
+        self.write(self.register(Register::Address), value)
+    }
+
+    /// Copies each block of data into the first page.
+    pub fn load(&mut self, data: &[DataBlock]) -> Result<(), Fault> {
+        for block in data {
+            let start = block.address as usize;
+            let end = start + block.words.len();
+            if let Some(cell) = (start..end).find(|cell| !self.contains(*cell)) {
+                return Err(Fault::AddressOutOfRange(cell));
+            }
+
+            self.cells[start..end].copy_from_slice(&block.words);
+        }
+
+        Ok(())
+    }
+
+    /// The address of the value `depth` values up from the bottom of the
+    /// stack.
+    fn stack_slot(&self, depth: usize) -> usize {
+        STACK_BOTTOM - depth
+    }
+
+    /// The address of slot `offset` in the current frame.
+    pub fn frame_slot(&self, offset: u16) -> Result<usize, Fault> {
+        let depth = self.register(Register::FramePointer) as usize + offset as usize;
+        if depth >= STACK_SIZE {
+            return Err(Fault::FrameSlotOutOfRange(offset));
+        }
+
+        Ok(self.stack_slot(depth))
+    }
+
+    pub fn stack_depth(&self) -> Result<usize, Fault> {
+        let depth = self.register(Register::StackPointer) as usize;
+        if depth > STACK_SIZE {
+            return Err(Fault::InvalidStackPointer);
+        }
+
+        Ok(depth)
+    }
+
+    pub fn push(&mut self, value: u16) -> Result<(), Fault> {
+        let depth = self.stack_depth()?;
+        if depth == STACK_SIZE {
+            return Err(Fault::StackOverflow);
+        }
+
+        self.set(self.stack_slot(depth), value);
+        self.set_register(Register::StackPointer, depth as u16 + 1);
+        Ok(())
+    }
+
+    /// Pushes `count` zeroes.
+    pub fn reserve(&mut self, count: u16) -> Result<(), Fault> {
+        let depth = self.stack_depth()?;
+        let new_depth = depth + count as usize;
+        if new_depth > STACK_SIZE {
+            return Err(Fault::StackOverflow);
+        }
+
+        for slot in depth..new_depth {
+            self.set(self.stack_slot(slot), 0);
+        }
+
+        self.set_register(Register::StackPointer, new_depth as u16);
+        Ok(())
+    }
+
+    pub fn peek(&self) -> Result<u16, Fault> {
+        match self.stack_depth()? {
+            0 => Err(Fault::StackUnderflow),
+            depth => Ok(self.cells[self.stack_slot(depth - 1)]),
+        }
+    }
+
+    pub fn pop(&mut self) -> Result<u16, Fault> {
+        let value = self.peek()?;
+        let depth = self.register(Register::StackPointer);
+        self.set_register(Register::StackPointer, depth - 1);
+        Ok(value)
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new(MEMORY_SIZE).expect("The default memory size is valid")
+    }
+}
+
+impl Index<usize> for Memory {
+    type Output = u16;
+
+    fn index(&self, index: usize) -> &u16 {
+        &self.cells[index]
+    }
+}
+
+impl IndexMut<usize> for Memory {
+    fn index_mut(&mut self, index: usize) -> &mut u16 {
+        &mut self.cells[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers() {
+        assert_eq!(Register::Address.address(), 0xFFFE);
+        assert_eq!(Register::FramePointer.address(), 0xFFFA);
+
+        // However much memory there is, the registers and stack stay put
+        for size in [MIN_MEMORY_SIZE, MEMORY_SIZE, 4 * PAGE_SIZE] {
+            let mut memory = Memory::new(size).unwrap();
+            memory.push(0x05).unwrap();
+            assert_eq!(memory.read(0xFFFB), Ok(0x01));
+            assert_eq!(memory.read(0xFFF9), Ok(0x05));
+        }
+    }
+
+    #[test]
+    fn sizes() {
+        assert!(matches!(
+            Memory::new(MIN_MEMORY_SIZE - 1),
+            Err(Error::InvalidMemorySize(1023))
+        ));
+        assert!(Memory::new(MAX_MEMORY_SIZE + 1).is_err());
+        assert_eq!(Memory::default().cells().len(), 65536);
+
+        let mut memory = Memory::new(MIN_MEMORY_SIZE).unwrap();
+        assert_eq!(memory.write(0x03FF, 0x01), Ok(()));
+        assert_eq!(
+            memory.write(0x0400, 0x01),
+            Err(Fault::AddressOutOfRange(0x0400))
+        );
+        assert_eq!(memory.write(0xFFFF, 0x01), Ok(()));
+    }
+
+    #[test]
+    fn paging() {
+        let mut memory = Memory::new(2 * PAGE_SIZE).unwrap();
+        memory.write(0xFFFF, 0x01).unwrap();
+        assert_eq!(memory[0xFFFF], 0x01);
+        assert_eq!(memory.page(), 0x00);
+
+        memory.set_page(0x01);
+        memory.write(0x0010, 0x02).unwrap();
+        assert_eq!(memory[PAGE_SIZE + 0x10], 0x02);
+        assert_eq!(memory[0x10], 0x00);
+
+        // The stack and registers are the same cells in every page
+        memory.push(0x03).unwrap();
+        assert_eq!(memory.read(0xFFF9), Ok(0x03));
+        assert_eq!(memory.read(0xFFFB), Ok(0x01));
+        memory.write(0xFFFF, 0x04).unwrap();
+        assert_eq!(memory[0xFFFF], 0x04);
+        assert_eq!(memory[PAGE_SIZE + 0xFFFF], 0x00);
+
+        memory.set_page(0x02);
+        assert_eq!(memory.read(0xFFFE), Ok(0x00));
+        assert_eq!(
+            memory.read(0x0000),
+            Err(Fault::AddressOutOfRange(2 * PAGE_SIZE))
+        );
+    }
+}
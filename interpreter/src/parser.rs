@@ -0,0 +1,1108 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    diagnostic::{Diagnostic, Diagnostics, SourceFile, Span},
+    instructions::{Instruction, InstructionError, Value},
+    lexer::{tokenize, Token, TokenKind},
+    program::{DataBlock, Program},
+    PAGE_SIZE, STACK_SIZE,
+};
+
+enum Operand {
+    Value(Value),
+    Name(String),
+}
+
+struct Statement {
+    mnemonic: String,
+    span: Span,
+    operand: Option<Operand>,
+}
+
+#[derive(Clone)]
+struct Macro {
+    span: Span,
+    params: Vec<String>,
+    body: Vec<Vec<Token>>,
+}
+
+/// How deeply macros may expand inside one another before the expansion is
+/// assumed to be infinitely recursive.
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+/// How many macro expansions a program may have in total, so that macros which
+/// each use the next more than once can't expand exponentially.
+const MAX_EXPANSIONS: usize = 100_000;
+
+#[derive(Default)]
+struct Parser {
+    statements: Vec<Statement>,
+    data: Vec<DataBlock>,
+    constants: HashMap<String, (u16, Span)>,
+    /// Every constant defined anywhere in the program or the files it
+    /// includes, used to tell a use before the definition apart from a
+    /// constant that does not exist.
+    definitions: HashSet<String>,
+    macros: HashMap<String, Macro>,
+    /// The macro currently being defined, between its `MAC` and `END`.
+    recording: Option<(String, Macro)>,
+    /// The call sites of the macros currently being expanded, innermost last.
+    expansions: Vec<(String, Span)>,
+    expansion_count: usize,
+    /// The files currently being included, outermost first, used to detect
+    /// include cycles.
+    including: Vec<(PathBuf, String)>,
+    /// Every file that has been included, so each is only included once.
+    included: HashSet<PathBuf>,
+    /// The files read so far, in the order they were first included.
+    files: Vec<Arc<SourceFile>>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+pub fn parse(file: Arc<SourceFile>) -> Result<Program, Diagnostics> {
+    let mut parser = Parser::default();
+    let path = fs::canonicalize(&file.path).unwrap_or_else(|_| PathBuf::from(&file.path));
+
+    let mut scanned = HashSet::from([path.clone()]);
+    scan_definitions(&file, &mut scanned, &mut parser.definitions);
+
+    parser.included.insert(path.clone());
+    parser.include(file, path);
+    parser.finish()
+}
+
+impl Parser {
+    fn include(&mut self, file: Arc<SourceFile>, path: PathBuf) {
+        self.files.push(file.clone());
+        let lines = match tokenize(&file) {
+            Ok(lines) => lines,
+            Err(Diagnostics(diagnostics)) => {
+                for diagnostic in diagnostics {
+                    self.report(diagnostic);
+                }
+
+                return;
+            }
+        };
+
+        self.including.push((path, file.path.clone()));
+        for line in lines {
+            self.process_line(line);
+        }
+        self.including.pop();
+
+        if let Some((name, definition)) = self.recording.take() {
+            self.report(Diagnostic::new(
+                format!("Macro {} is missing its END", name),
+                &definition.span,
+            ));
+        }
+    }
+
+    fn process_line(&mut self, line: Vec<Token>) {
+        if let Some((_, definition)) = &mut self.recording {
+            match keyword(&line) {
+                Some("END") => {
+                    let (name, definition) = self.recording.take().unwrap();
+                    self.macros.insert(name, definition);
+
+                    if let Err(diagnostic) = expect_end(line.into_iter().skip(1)) {
+                        self.report(diagnostic);
+                    }
+                }
+                Some("MAC") => self.report(Diagnostic::new(
+                    "Macros cannot be defined inside other macros",
+                    &line[0].span,
+                )),
+                _ => definition.body.push(line),
+            }
+
+            return;
+        }
+
+        if let Err(diagnostic) = self.parse_line(line) {
+            self.report(diagnostic);
+        }
+    }
+
+    fn parse_line(&mut self, line: Vec<Token>) -> Result<(), Diagnostic> {
+        let mut tokens = line.into_iter();
+        let first = tokens.next().expect("Lines are never empty");
+
+        let TokenKind::Identifier(mnemonic) = first.kind else {
+            return Err(Diagnostic::new("Expected instruction", &first.span));
+        };
+
+        match mnemonic.as_str() {
+            "DEF" => return self.parse_definition(&first.span, tokens),
+            "MAC" => return self.parse_macro(&first.span, tokens),
+            "END" => return Err(Diagnostic::new("END without a matching MAC", &first.span)),
+            "INC" => return self.parse_include(&first.span, tokens),
+            "DAT" => return self.parse_data(&first.span, tokens),
+            _ => {}
+        }
+
+        if self.macros.contains_key(&mnemonic) {
+            return self.expand(mnemonic, first.span, tokens);
+        }
+
+        let operand = self.parse_operand(&mnemonic, &mut tokens)?;
+        expect_end(tokens)?;
+
+        let (operand, operand_span) = match operand {
+            Some((operand, span)) => (Some(operand), Some(span)),
+            None => (None, None),
+        };
+
+        // Names are only resolved once every label is known, so check the
+        // instruction now with a placeholder in their place
+        let placeholder = operand.as_ref().map(|operand| match operand {
+            Operand::Value(value) => *value,
+            Operand::Name(_) => Value::Literal(0),
+        });
+
+        if let Err(err) = Instruction::from_string(&mnemonic, placeholder) {
+            let span = match (&err, &operand_span) {
+                (InstructionError::UnexpectedOperand(_), Some(operand_span)) => operand_span,
+                _ => &first.span,
+            };
+
+            return Err(Diagnostic::new(err.to_string(), span));
+        }
+
+        // Cover the operand too, unless it came from somewhere else such as a
+        // macro argument
+        let span = match &operand_span {
+            Some(operand_span)
+                if Arc::ptr_eq(&operand_span.file, &first.span.file)
+                    && operand_span.line == first.span.line =>
+            {
+                first.span.to(operand_span)
+            }
+            _ => first.span,
+        };
+
+        self.statements.push(Statement {
+            mnemonic,
+            span,
+            operand,
+        });
+
+        Ok(())
+    }
+
+    fn parse_include(
+        &mut self,
+        keyword: &Span,
+        mut tokens: impl Iterator<Item = Token>,
+    ) -> Result<(), Diagnostic> {
+        let Some(Token {
+            kind: TokenKind::String(relative),
+            span,
+        }) = tokens.next()
+        else {
+            return Err(Diagnostic::new("Expected file path after INC", keyword));
+        };
+
+        expect_end(tokens)?;
+
+        let path = include_path(&span.file, &relative);
+        let display = path.display().to_string();
+        let not_found = |err: std::io::Error| {
+            Diagnostic::new(format!("Could not include {}: {}", display, err), &span)
+        };
+
+        let canonical = fs::canonicalize(&path).map_err(not_found)?;
+
+        if let Some(start) = self
+            .including
+            .iter()
+            .position(|(path, _)| *path == canonical)
+        {
+            let cycle: Vec<&str> = self.including[start..]
+                .iter()
+                .map(|(_, display)| display.as_str())
+                .chain([display.as_str()])
+                .collect();
+
+            return Err(Diagnostic::new(
+                format!("Include cycle {}", cycle.join(" -> ")),
+                &span,
+            ));
+        }
+
+        if !self.included.insert(canonical.clone()) {
+            return Ok(());
+        }
+
+        let text = fs::read_to_string(&path).map_err(not_found)?;
+        self.include(
+            Arc::new(SourceFile {
+                path: display,
+                text,
+            }),
+            canonical,
+        );
+
+        Ok(())
+    }
+
+    fn parse_macro(
+        &mut self,
+        keyword: &Span,
+        mut tokens: impl Iterator<Item = Token>,
+    ) -> Result<(), Diagnostic> {
+        let Some(Token {
+            kind: TokenKind::Identifier(name),
+            span,
+        }) = tokens.next()
+        else {
+            return Err(Diagnostic::new("Expected macro name after MAC", keyword));
+        };
+
+        if matches!(name.as_str(), "DEF" | "MAC" | "END" | "INC" | "DAT")
+            || !matches!(
+                Instruction::from_string(&name, None),
+                Err(InstructionError::UnknownMnemonic(_))
+            )
+        {
+            return Err(Diagnostic::new(
+                format!("Macro name {} is reserved", name),
+                &span,
+            ));
+        }
+
+        if let Some(previous) = self.macros.get(&name) {
+            return Err(Diagnostic::new(
+                format!("Macro {} is already defined at {}", name, previous.span),
+                &span,
+            ));
+        }
+
+        let mut params: Vec<String> = Vec::new();
+        for token in tokens {
+            match token.kind {
+                TokenKind::Identifier(param) if !params.contains(&param) => params.push(param),
+                TokenKind::Identifier(param) => {
+                    return Err(Diagnostic::new(
+                        format!("Duplicate macro parameter {}", param),
+                        &token.span,
+                    ))
+                }
+                _ => return Err(Diagnostic::new("Expected parameter name", &token.span)),
+            }
+        }
+
+        self.recording = Some((
+            name,
+            Macro {
+                span,
+                params,
+                body: Vec::new(),
+            },
+        ));
+
+        Ok(())
+    }
+
+    fn expand(
+        &mut self,
+        name: String,
+        call: Span,
+        tokens: impl Iterator<Item = Token>,
+    ) -> Result<(), Diagnostic> {
+        let definition = self.macros[&name].clone();
+
+        // A `*` or `@` belongs to the argument that follows it
+        let mut args: Vec<Vec<Token>> = Vec::new();
+        let mut call_span = call.clone();
+        for token in tokens {
+            call_span = call.to(&token.span);
+            match args.last_mut() {
+                Some(arg) if matches!(arg[arg.len() - 1].kind, TokenKind::Star | TokenKind::At) => {
+                    arg.push(token)
+                }
+                _ => args.push(vec![token]),
+            }
+        }
+
+        if args.len() != definition.params.len() {
+            return Err(Diagnostic::new(
+                format!(
+                    "Macro {} takes {} argument(s) but {} were given",
+                    name,
+                    definition.params.len(),
+                    args.len()
+                ),
+                &call_span,
+            ));
+        }
+
+        if self.expansions.len() >= MAX_EXPANSION_DEPTH {
+            return Err(Diagnostic::new(
+                format!("Macro {} expands recursively", name),
+                &call,
+            ));
+        }
+
+        // Once the limit is reached only the first expansion past it is
+        // reported, rather than every call still to come
+        self.expansion_count += 1;
+        if self.expansion_count > MAX_EXPANSIONS {
+            if self.expansion_count > MAX_EXPANSIONS + 1 {
+                return Ok(());
+            }
+
+            return Err(Diagnostic::new(
+                format!(
+                    "Macro {} expands the program more than {} times",
+                    name, MAX_EXPANSIONS
+                ),
+                &call,
+            ));
+        }
+
+        // Labels and functions declared inside the body get a name unique to
+        // this expansion, so the macro can be used more than once. Expansions
+        // nested in the body count too, so take the id before they happen
+        let id = self.expansion_count;
+        let labels: HashSet<&String> = definition
+            .body
+            .iter()
+            .filter_map(|line| match line.as_slice() {
+                [Token {
+                    kind: TokenKind::Identifier(keyword),
+                    ..
+                }, Token {
+                    kind: TokenKind::Identifier(label),
+                    ..
+                }, ..]
+                    if matches!(keyword.as_str(), "LAB" | "FUN")
+                        && !definition.params.contains(label) =>
+                {
+                    Some(label)
+                }
+                _ => None,
+            })
+            .collect();
+
+        self.expansions
+            .push((format!("In expansion of macro {}", name), call_span));
+
+        for line in &definition.body {
+            let mut expanded: Vec<Token> = Vec::new();
+            for token in line {
+                match &token.kind {
+                    TokenKind::Identifier(ident) if labels.contains(ident) => {
+                        expanded.push(Token {
+                            kind: TokenKind::Identifier(format!("{}#{}", ident, id)),
+                            span: token.span.clone(),
+                        })
+                    }
+                    TokenKind::Identifier(ident) => {
+                        match definition.params.iter().position(|p| p == ident) {
+                            Some(i) => expanded.extend(args[i].iter().cloned()),
+                            None => expanded.push(token.clone()),
+                        }
+                    }
+                    _ => expanded.push(token.clone()),
+                }
+            }
+
+            self.process_line(expanded);
+        }
+
+        self.expansions.pop();
+        Ok(())
+    }
+
+    fn parse_definition(
+        &mut self,
+        keyword: &Span,
+        mut tokens: impl Iterator<Item = Token>,
+    ) -> Result<(), Diagnostic> {
+        let Some(Token {
+            kind: TokenKind::Identifier(name),
+            span,
+        }) = tokens.next()
+        else {
+            return Err(Diagnostic::new("Expected constant name after DEF", keyword));
+        };
+
+        let value = match tokens.next() {
+            Some(Token {
+                kind: TokenKind::Number(value),
+                ..
+            }) => value,
+            Some(Token {
+                kind: TokenKind::Identifier(other),
+                span,
+            }) => self.defined_constant(&other, &span)?,
+            _ => {
+                return Err(Diagnostic::new(
+                    format!("Expected value for constant {}", name),
+                    &span,
+                ))
+            }
+        };
+
+        expect_end(tokens)?;
+
+        if let Some((_, previous)) = self.constants.get(&name) {
+            return Err(Diagnostic::new(
+                format!("Constant {} is already defined at {}", name, previous),
+                &span,
+            ));
+        }
+
+        self.constants.insert(name, (value, span));
+        Ok(())
+    }
+
+    fn parse_data(
+        &mut self,
+        keyword: &Span,
+        mut tokens: impl Iterator<Item = Token>,
+    ) -> Result<(), Diagnostic> {
+        let (address, address_span) = match tokens.next() {
+            Some(Token {
+                kind: TokenKind::Number(address),
+                span,
+            }) => (address, span),
+            Some(Token {
+                kind: TokenKind::Identifier(name),
+                span,
+            }) => (self.defined_constant(&name, &span)?, span),
+            _ => return Err(Diagnostic::new("Expected address after DAT", keyword)),
+        };
+
+        let mut words: Vec<u16> = Vec::new();
+        let mut span = address_span.clone();
+
+        for token in tokens {
+            match token.kind {
+                TokenKind::Number(word) => words.push(word),
+                TokenKind::Identifier(name) => {
+                    words.push(self.defined_constant(&name, &token.span)?)
+                }
+                TokenKind::String(string) => {
+                    for c in string.chars() {
+                        words.push(u16::try_from(c as u32).map_err(|_| {
+                            Diagnostic::new(
+                                format!("Character {} does not fit in 16 bits", c),
+                                &token.span,
+                            )
+                        })?);
+                    }
+                }
+                TokenKind::Star | TokenKind::At => {
+                    return Err(Diagnostic::new(
+                        "DAT values cannot be addresses",
+                        &token.span,
+                    ))
+                }
+            }
+
+            span = address_span.to(&token.span);
+        }
+
+        if words.is_empty() {
+            return Err(Diagnostic::new("Expected values after DAT address", &span));
+        }
+
+        if address as usize + words.len() > PAGE_SIZE {
+            return Err(Diagnostic::new("Data does not fit in memory", &span));
+        }
+
+        self.data.push(DataBlock { address, words });
+        Ok(())
+    }
+
+    fn parse_operand(
+        &self,
+        mnemonic: &str,
+        tokens: &mut impl Iterator<Item = Token>,
+    ) -> Result<Option<(Operand, Span)>, Diagnostic> {
+        let Some(mut token) = tokens.next() else {
+            return Ok(None);
+        };
+
+        let mut span = token.span.clone();
+        let is_address = token.kind == TokenKind::Star;
+
+        if is_address {
+            token = match tokens.next() {
+                Some(next) if next.kind != TokenKind::Star => next,
+                _ => return Err(Diagnostic::new("Expected address after *", &span)),
+            };
+            span = span.to(&token.span);
+        }
+
+        let frame_span = token.span.clone();
+        let is_frame = token.kind == TokenKind::At;
+
+        if is_frame {
+            token = match tokens.next() {
+                Some(next)
+                    if matches!(next.kind, TokenKind::Number(_) | TokenKind::Identifier(_)) =>
+                {
+                    next
+                }
+                _ => return Err(Diagnostic::new("Expected frame offset after @", &span)),
+            };
+            span = span.to(&token.span);
+        }
+
+        let value = |value: u16| {
+            if is_frame && value as usize >= STACK_SIZE {
+                return Err(Diagnostic::new(
+                    format!("Frame offset {} is outside of the stack", value),
+                    &frame_span.to(&token.span),
+                ));
+            }
+
+            Ok(Operand::Value(match (is_address, is_frame) {
+                (false, false) => Value::Literal(value),
+                (true, false) => Value::Address(value),
+                (false, true) => Value::Frame(value),
+                (true, true) => Value::FrameAddress(value),
+            }))
+        };
+
+        let operand = match token.kind {
+            TokenKind::Number(number) => value(number)?,
+            TokenKind::Identifier(ref name) => match self.constant(name, &token.span)? {
+                Some(constant) => value(constant)?,
+                None if !Instruction::takes_label(mnemonic) || is_frame => {
+                    return Err(Diagnostic::new(
+                        format!("Unknown constant {}", name),
+                        &token.span,
+                    ))
+                }
+                None if is_address => {
+                    return Err(Diagnostic::new(
+                        format!("Label {} cannot be used as an address", name),
+                        &span,
+                    ))
+                }
+                None => Operand::Name(name.clone()),
+            },
+            TokenKind::String(_) | TokenKind::Star | TokenKind::At => {
+                return Err(Diagnostic::new(
+                    format!("{} instruction does not take a string", mnemonic),
+                    &token.span,
+                ))
+            }
+        };
+
+        Ok(Some((operand, span)))
+    }
+
+    /// Looks up a constant, returning `None` if no constant by that name is
+    /// defined anywhere in the program.
+    fn constant(&self, name: &str, span: &Span) -> Result<Option<u16>, Diagnostic> {
+        if let Some((value, _)) = self.constants.get(name) {
+            Ok(Some(*value))
+        } else if self.definitions.contains(name) {
+            Err(Diagnostic::new(
+                format!("Constant {} used before its definition", name),
+                span,
+            ))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn report(&mut self, mut diagnostic: Diagnostic) {
+        diagnostic
+            .notes
+            .extend(self.expansions.iter().rev().cloned());
+        self.diagnostics.push(diagnostic);
+    }
+
+    fn defined_constant(&self, name: &str, span: &Span) -> Result<u16, Diagnostic> {
+        self.constant(name, span)?
+            .ok_or_else(|| Diagnostic::new(format!("Unknown constant {}", name), span))
+    }
+
+    fn finish(mut self) -> Result<Program, Diagnostics> {
+        if !self.diagnostics.is_empty() {
+            // Order by where the error appears, file by file, using the
+            // outermost macro invocation for errors inside expansions
+            let files = &self.files;
+            self.diagnostics.sort_by_key(|diagnostic| {
+                let span = diagnostic
+                    .notes
+                    .last()
+                    .map(|(_, span)| span)
+                    .or(diagnostic.span.as_ref());
+                span.map(|span| {
+                    let file = files.iter().position(|file| Arc::ptr_eq(file, &span.file));
+                    (file, span.line, span.column)
+                })
+            });
+            return Err(Diagnostics(self.diagnostics));
+        }
+
+        let names = resolve_names(&self.statements);
+        let mut program = Program {
+            data: self.data,
+            // Labels renamed for a macro expansion are shown by their name in
+            // the macro
+            names: names
+                .iter()
+                .map(|(name, id)| (*id, name.split('#').next().unwrap_or(name).to_string()))
+                .collect(),
+            ..Default::default()
+        };
+
+        for statement in self.statements {
+            let value = statement.operand.map(|operand| match operand {
+                Operand::Value(value) => value,
+                Operand::Name(name) => Value::Literal(names[&name]),
+            });
+
+            program.instructions.push(
+                Instruction::from_string(&statement.mnemonic, value)
+                    .expect("Statements are checked when parsed"),
+            );
+            program.spans.push(statement.span);
+        }
+
+        Ok(program)
+    }
+}
+
+fn keyword(line: &[Token]) -> Option<&str> {
+    match line.first() {
+        Some(Token {
+            kind: TokenKind::Identifier(keyword),
+            ..
+        }) => Some(keyword),
+        _ => None,
+    }
+}
+
+fn expect_end(mut tokens: impl Iterator<Item = Token>) -> Result<(), Diagnostic> {
+    if let Some(extra) = tokens.next() {
+        let span = match tokens.last() {
+            Some(last) => extra.span.to(&last.span),
+            None => extra.span,
+        };
+
+        return Err(Diagnostic::new("Unexpected tokens after operand", &span));
+    }
+
+    Ok(())
+}
+
+/// Finds the file an `INC` refers to. Paths are relative to the file containing
+/// the `INC`.
+fn include_path(file: &SourceFile, relative: &str) -> PathBuf {
+    Path::new(&file.path)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(relative)
+}
+
+/// Collects the name of every constant defined in `file` or any file it
+/// includes, before any of them are parsed. Files that can't be read or
+/// tokenized are skipped here, as parsing reports them.
+fn scan_definitions(
+    file: &Arc<SourceFile>,
+    scanned: &mut HashSet<PathBuf>,
+    definitions: &mut HashSet<String>,
+) {
+    let Ok(lines) = tokenize(file) else {
+        return;
+    };
+
+    for line in &lines {
+        let [Token {
+            kind: TokenKind::Identifier(keyword),
+            ..
+        }, operand, ..] = line.as_slice()
+        else {
+            continue;
+        };
+
+        match (keyword.as_str(), &operand.kind) {
+            ("DEF", TokenKind::Identifier(name)) => {
+                definitions.insert(name.clone());
+            }
+            ("INC", TokenKind::String(relative)) => {
+                let path = include_path(file, relative);
+                let Ok(canonical) = fs::canonicalize(&path) else {
+                    continue;
+                };
+
+                if !scanned.insert(canonical) {
+                    continue;
+                }
+
+                if let Ok(text) = fs::read_to_string(&path) {
+                    let file = Arc::new(SourceFile {
+                        path: path.display().to_string(),
+                        text,
+                    });
+                    scan_definitions(&file, scanned, definitions);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Assigns each symbolic label or function name a numeric id, skipping any
+/// ids already used by numeric `LAB`/`FUN` declarations.
+fn resolve_names(statements: &[Statement]) -> HashMap<String, u16> {
+    let used: HashSet<u16> = statements
+        .iter()
+        .filter_map(
+            |statement| match (statement.mnemonic.as_str(), &statement.operand) {
+                ("LAB" | "FUN", Some(Operand::Value(Value::Literal(id)))) => Some(*id),
+                _ => None,
+            },
+        )
+        .collect();
+
+    let mut names: HashMap<String, u16> = HashMap::new();
+    let mut next_id: u16 = 0;
+
+    for statement in statements {
+        if let Some(Operand::Name(name)) = &statement.operand {
+            if names.contains_key(name) {
+                continue;
+            }
+
+            while used.contains(&next_id) {
+                next_id += 1;
+            }
+
+            names.insert(name.clone(), next_id);
+            next_id += 1;
+        }
+    }
+
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{
+        compiler::compile,
+        diagnostic::SourceFile,
+        instructions::{Instruction, Value},
+        lexer::{decode, lex_source},
+        program::DataBlock,
+    };
+
+    fn error(text: &str) -> String {
+        lex_source(SourceFile {
+            path: "test.jasm".to_string(),
+            text: text.to_string(),
+        })
+        .unwrap_err()
+        .to_string()
+    }
+
+    #[test]
+    fn unknown_instruction_diagnostic() {
+        assert_eq!(
+            error("SEA 0x10\n    FOO 0x01\n"),
+            "error: Unknown token FOO\n --> test.jasm:2:5\n  |\n2 |     FOO 0x01\n  |     ^^^"
+        );
+    }
+
+    #[test]
+    fn operand_arity_diagnostics() {
+        assert!(error("SEA\n").contains("SEA instruction requires value\n --> test.jasm:1:1"));
+        assert!(error("OUT *0x01\n")
+            .contains("OUT instruction does not take a value\n --> test.jasm:1:5"));
+        assert!(error("SEA 0x01 0x02 0x03\n")
+            .ends_with("1 | SEA 0x01 0x02 0x03\n  |          ^^^^^^^^^"));
+    }
+
+    #[test]
+    fn constants() {
+        let instructions = lex_source(SourceFile {
+            path: "test.jasm".to_string(),
+            text: "DEF COUNTER 0x02\nDEF START COUNTER\nSEA *COUNTER\nSET START\n".to_string(),
+        })
+        .unwrap()
+        .instructions;
+
+        assert!(matches!(
+            instructions.as_slice(),
+            [
+                Instruction::SetAddress(Value::Address(0x02)),
+                Instruction::SetValue(Value::Literal(0x02)),
+            ]
+        ));
+    }
+
+    #[test]
+    fn symbolic_names() {
+        let program = lex_source(SourceFile {
+            path: "test.jasm".to_string(),
+            text: "LAB 0x00\nFUN print_newline\nEFN\nLAB loop_start\nCAL print_newline\nJMP loop_start\nBNE 0x00\n"
+                .to_string(),
+        })
+        .unwrap();
+
+        // Names are numbered in order of first use, skipping numeric ids
+        let expected = |instructions: &[Instruction]| {
+            matches!(
+                instructions,
+                [
+                    Instruction::Label(Value::Literal(0x00)),
+                    Instruction::Function(Value::Literal(0x01)),
+                    Instruction::EndFunction,
+                    Instruction::Label(Value::Literal(0x02)),
+                    Instruction::Call(Value::Literal(0x01)),
+                    Instruction::Jump(Value::Literal(0x02)),
+                    Instruction::BranchIfNotEqual(Value::Literal(0x00)),
+                ]
+            )
+        };
+        assert!(expected(&program.instructions));
+
+        // Compiled programs only keep the ids
+        let compiled = decode(&compile(program)).unwrap();
+        assert!(expected(&compiled.instructions));
+    }
+
+    #[test]
+    fn constant_diagnostics() {
+        assert!(error("DEF A 0x01\nDEF A 0x02\n")
+            .contains("Constant A is already defined at test.jasm:1:5\n --> test.jasm:2:5"));
+        assert!(error("SET A\nDEF A 0x01\n")
+            .contains("Constant A used before its definition\n --> test.jasm:1:5"));
+        assert!(error("JMP A\nDEF A 0x01\n")
+            .contains("Constant A used before its definition\n --> test.jasm:1:5"));
+        assert!(error("SET A\n").contains("Unknown constant A\n --> test.jasm:1:5"));
+    }
+
+    #[test]
+    fn macro_labels_are_hygienic() {
+        let instructions = lex_source(SourceFile {
+            path: "test.jasm".to_string(),
+            text: "MAC LOOP cell\nLAB top\nSEA *cell\nBNE top\nEND\nLOOP 0x01\nLOOP 0x02\n"
+                .to_string(),
+        })
+        .unwrap()
+        .instructions;
+
+        let labels: Vec<Value> = instructions
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Label(id) | Instruction::BranchIfNotEqual(id) => Some(*id),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(
+            labels,
+            vec![
+                Value::Literal(0),
+                Value::Literal(0),
+                Value::Literal(1),
+                Value::Literal(1)
+            ]
+        );
+        assert!(matches!(
+            instructions[4],
+            Instruction::SetAddress(Value::Address(0x02))
+        ));
+
+        // A macro used inside the body doesn't change the body's own labels
+        let nested = lex_source(SourceFile {
+            path: "test.jasm".to_string(),
+            text: "MAC INNER\nADD 1\nEND\nMAC LOOP\nLAB top\nINNER\nCEQ 5\nBNE top\nEND\nLOOP\n"
+                .to_string(),
+        })
+        .unwrap()
+        .instructions;
+
+        assert!(matches!(
+            nested.as_slice(),
+            [
+                Instruction::Label(Value::Literal(0)),
+                Instruction::Add(Value::Literal(1)),
+                Instruction::Compare(Value::Literal(5)),
+                Instruction::BranchIfNotEqual(Value::Literal(0)),
+            ]
+        ));
+    }
+
+    #[test]
+    fn macro_diagnostics() {
+        let expansion = error("MAC SET_TO value\nSET value\nCUT 0x01\nEND\nSET_TO 0x01\n");
+        assert!(expansion.contains("CUT instruction does not take a value\n --> test.jasm:3:5"));
+        assert!(expansion.contains("note: In expansion of macro SET_TO\n --> test.jasm:5:1"));
+
+        assert!(error("MAC A\nB\nEND\nMAC B\nA\nEND\nA\n").contains("expands recursively"));
+
+        let mut source = "MAC M0\nSET 0x01\nEND\n".to_string();
+        for i in 1..20 {
+            source += &format!("MAC M{}\nM{}\nM{}\nEND\n", i, i - 1, i - 1);
+        }
+        source += "M19\n";
+        let exponential = error(&source);
+        assert!(exponential.contains("expands the program more than 100000 times"));
+        assert_eq!(exponential.matches("more than 100000 times").count(), 1);
+        assert!(error("MAC A\nSET 0x01\n").contains("Macro A is missing its END"));
+        assert!(error("MAC SET\nEND\n").contains("Macro name SET is reserved"));
+        assert!(error("MAC INC\nEND\n").contains("Macro name INC is reserved"));
+        assert!(error("MAC DAT\nEND\n").contains("Macro name DAT is reserved"));
+        assert!(error("MAC A x\nEND\nA\n").contains("Macro A takes 1 argument(s) but 0 were given"));
+    }
+
+    #[test]
+    fn includes() {
+        let dir = std::env::temp_dir().join(format!("jasm-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(
+            dir.join("lib/io.jasm"),
+            "INC \"consts.jasm\"\nFUN newline\nRTN\n",
+        )
+        .unwrap();
+        fs::write(dir.join("lib/consts.jasm"), "DEF CELL 0x03\n").unwrap();
+        fs::write(dir.join("cycle.jasm"), "INC \"cycle.jasm\"\n").unwrap();
+
+        let instructions = lex_source(SourceFile {
+            path: dir.join("main.jasm").display().to_string(),
+            text: "INC \"lib/io.jasm\"\nINC \"lib/io.jasm\"\nSEA CELL\nJMP newline\n".to_string(),
+        })
+        .unwrap()
+        .instructions;
+
+        assert!(matches!(
+            instructions.as_slice(),
+            [
+                Instruction::Function(Value::Literal(0)),
+                Instruction::Return,
+                Instruction::SetAddress(Value::Literal(0x03)),
+                Instruction::Jump(Value::Literal(0)),
+            ]
+        ));
+
+        let cycle = lex_source(SourceFile {
+            path: dir.join("main.jasm").display().to_string(),
+            text: "INC \"cycle.jasm\"\n".to_string(),
+        })
+        .unwrap_err()
+        .to_string();
+        assert!(cycle.contains("Include cycle"));
+
+        let missing = lex_source(SourceFile {
+            path: dir.join("main.jasm").display().to_string(),
+            text: "INC \"missing.jasm\"\n".to_string(),
+        })
+        .unwrap_err()
+        .to_string();
+        assert!(missing.contains("Could not include"));
+
+        fs::write(dir.join("later.jasm"), "DEF LATER 0x01\n").unwrap();
+        let later = lex_source(SourceFile {
+            path: dir.join("main.jasm").display().to_string(),
+            text: "SET LATER\nINC \"later.jasm\"\n".to_string(),
+        })
+        .unwrap_err()
+        .to_string();
+        assert!(later.contains("Constant LATER used before its definition"));
+
+        fs::write(dir.join("broken.jasm"), "BAR 0x01\n").unwrap();
+        let ordered = lex_source(SourceFile {
+            path: dir.join("main.jasm").display().to_string(),
+            text: "INC \"broken.jasm\"\nFOO 0x01\n".to_string(),
+        })
+        .unwrap_err()
+        .to_string();
+        assert!(ordered.find("FOO").unwrap() < ordered.find("BAR").unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn frame_operands() {
+        let instructions = lex_source(SourceFile {
+            path: "test.jasm".to_string(),
+            text: "DEF COUNT 1\nSEA @0\nADD *@COUNT\nMAC SET_TO slot\nSET slot\nEND\nSET_TO *@2\n"
+                .to_string(),
+        })
+        .unwrap()
+        .instructions;
+
+        assert!(matches!(
+            instructions[..],
+            [
+                Instruction::SetAddress(Value::Frame(0)),
+                Instruction::Add(Value::FrameAddress(1)),
+                Instruction::SetValue(Value::FrameAddress(2)),
+            ]
+        ));
+
+        assert!(error("SEA @\n").contains("Expected frame offset after @\n --> test.jasm:1:5"));
+        assert!(error("SEA *@*0x01\n").contains("Expected frame offset after @"));
+        assert!(error("JMP @loop\n").contains("Unknown constant loop"));
+        assert!(error("SEA @256\n").contains("Frame offset 256 is outside of the stack"));
+
+        // Arguments on a shorter line than the body still get their own span
+        let indented = lex_source(SourceFile {
+            path: "test.jasm".to_string(),
+            text: "MAC M slot\n        SET *slot\n        SEA @slot\nEND\nM 0x10\n".to_string(),
+        })
+        .unwrap();
+        assert!(matches!(
+            indented.instructions[..],
+            [
+                Instruction::SetValue(Value::Address(0x10)),
+                Instruction::SetAddress(Value::Frame(0x10)),
+            ]
+        ));
+        assert!(error("MAC M slot\n        SEA @slot\nEND\nM 256\n")
+            .contains("Frame offset 256 is outside of the stack\n --> test.jasm:4:3"));
+    }
+
+    #[test]
+    fn data() {
+        let program = lex_source(SourceFile {
+            path: "test.jasm".to_string(),
+            text: "DEF TABLE 0x200\nDAT 0x100 \"Hi\\n\" 0\nDAT TABLE 0x01 -1 TABLE\n".to_string(),
+        })
+        .unwrap();
+
+        assert_eq!(
+            program.data,
+            vec![
+                DataBlock {
+                    address: 0x100,
+                    words: vec![0x48, 0x69, 0x0A, 0x00],
+                },
+                DataBlock {
+                    address: 0x200,
+                    words: vec![0x01, 0xFFFF, 0x200],
+                },
+            ]
+        );
+
+        assert!(error("DAT 0x100\n").contains("Expected values after DAT address"));
+        assert!(error("DAT 0xFFFE 0x01 0x02 0x03\n").contains("Data does not fit in memory"));
+    }
+
+    #[test]
+    fn reports_every_error() {
+        let error = error("FOO 0x01\nSET 0x01\nSET foo\n");
+        assert!(error.contains("test.jasm:1:1"));
+        assert!(error.contains("test.jasm:3:5"));
+        assert!(!error.contains("test.jasm:2:"));
+    }
+}
@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+
+use crate::{diagnostic::Span, instructions::Instruction};
+
+/// Words written into memory at `address` before the program starts running.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataBlock {
+    pub address: u16,
+    pub words: Vec<u16>,
+}
+
+/// How the body of a function is terminated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FunctionEnd {
+    /// The `EFN` at this index.
+    Marker(usize),
+    /// Another function is declared, at this index, before the body ends.
+    Nested(usize),
+    /// The program ends before the body does.
+    Missing,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    pub data: Vec<DataBlock>,
+    /// The source location of each instruction. Empty for programs loaded
+    /// from `.jasmb` files.
+    pub spans: Vec<Span>,
+    /// The name each label and function id was given in the source, for those
+    /// declared or used by name rather than by number.
+    pub names: HashMap<u16, String>,
+}
+
+impl Program {
+    /// Finds where the body of each function ends, keyed by the index of its
+    /// `FUN`.
+    pub fn function_ends(&self) -> HashMap<usize, FunctionEnd> {
+        let mut ends = HashMap::new();
+        // The `FUN` of the function being read
+        let mut open: Option<usize> = None;
+
+        for (i, instruction) in self.instructions.iter().enumerate() {
+            match instruction {
+                Instruction::Function(_) => {
+                    if let Some(start) = open {
+                        ends.insert(start, FunctionEnd::Nested(i));
+                    }
+
+                    open = Some(i);
+                }
+                Instruction::EndFunction => {
+                    if let Some(start) = open.take() {
+                        ends.insert(start, FunctionEnd::Marker(i));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(start) = open {
+            ends.insert(start, FunctionEnd::Missing);
+        }
+
+        ends
+    }
+}
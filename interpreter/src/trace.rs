@@ -0,0 +1,172 @@
+use std::{fmt::Write, str::FromStr};
+
+use crate::{diagnostic::Span, instructions::Instruction};
+
+/// How each line of a trace is written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "text" => Ok(TraceFormat::Text),
+            "json" => Ok(TraceFormat::Json),
+            _ => Err(format!(
+                "Unknown trace format {}, expected text or json",
+                format
+            )),
+        }
+    }
+}
+
+/// A write to memory by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Change {
+    pub cell: usize,
+    pub old: u16,
+    pub new: u16,
+}
+
+/// An instruction that was run, and what it did.
+pub struct TraceEvent<'a> {
+    pub index: usize,
+    /// Where the instruction is in the source, for programs that came from
+    /// source.
+    pub span: Option<&'a Span>,
+    pub instruction: &'a Instruction,
+    /// The value of the instruction's operand when it was run.
+    pub operand: Option<u16>,
+    /// The value of the address register when the instruction was run.
+    pub address: u16,
+    /// Every cell the instruction changed, registers included, in the order it
+    /// first wrote to them.
+    pub changes: Vec<Change>,
+    /// How many calls deep the instruction was run.
+    pub depth: usize,
+}
+
+impl TraceEvent<'_> {
+    pub fn format(&self, format: TraceFormat) -> String {
+        match format {
+            TraceFormat::Text => self.text(),
+            TraceFormat::Json => self.json(),
+        }
+    }
+
+    fn text(&self) -> String {
+        let mut line = format!("[{}]", self.index);
+        if let Some(span) = self.span {
+            write!(line, " {}", span).unwrap();
+        }
+
+        write!(line, " {}", self.instruction).unwrap();
+        if let Some(operand) = self.operand {
+            write!(line, " = {:#06x}", operand).unwrap();
+        }
+
+        write!(line, "  ADDRESS {:#06x}", self.address).unwrap();
+        for change in &self.changes {
+            write!(
+                line,
+                "  [{:#06x}] {:#06x} -> {:#06x}",
+                change.cell, change.old, change.new
+            )
+            .unwrap();
+        }
+
+        write!(line, "  depth {}", self.depth).unwrap();
+        line
+    }
+
+    fn json(&self) -> String {
+        let source = self
+            .span
+            .map_or("null".to_string(), |span| json_string(&span.to_string()));
+        let operand = self
+            .operand
+            .map_or("null".to_string(), |operand| operand.to_string());
+        let changes: Vec<String> = self
+            .changes
+            .iter()
+            .map(|change| {
+                format!(
+                    "{{\"cell\":{},\"old\":{},\"new\":{}}}",
+                    change.cell, change.old, change.new
+                )
+            })
+            .collect();
+
+        format!(
+            "{{\"index\":{},\"source\":{},\"instruction\":{},\"operand\":{},\"address\":{},\"changes\":[{}],\"depth\":{}}}",
+            self.index,
+            source,
+            json_string(&self.instruction.to_string()),
+            operand,
+            self.address,
+            changes.join(","),
+            self.depth
+        )
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut string = String::from('"');
+    for char in text.chars() {
+        match char {
+            '"' => string.push_str("\\\""),
+            '\\' => string.push_str("\\\\"),
+            char if char.is_control() => write!(string, "\\u{:04x}", char as u32).unwrap(),
+            char => string.push(char),
+        }
+    }
+
+    string.push('"');
+    string
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions::Value;
+
+    #[test]
+    fn formats() {
+        let instruction = Instruction::Subtract(Value::Address(0x11));
+        let event = TraceEvent {
+            index: 4,
+            span: None,
+            instruction: &instruction,
+            operand: Some(0x01),
+            address: 0x02,
+            changes: vec![
+                Change {
+                    cell: 0x02,
+                    old: 0x05,
+                    new: 0x04,
+                },
+                Change {
+                    cell: 0xFFFC,
+                    old: 0x04,
+                    new: 0x00,
+                },
+            ],
+            depth: 1,
+        };
+
+        assert_eq!(
+            event.format(TraceFormat::Text),
+            "[4] SUB *0x0011 = 0x0001  ADDRESS 0x0002  [0x0002] 0x0005 -> 0x0004  [0xfffc] 0x0004 -> 0x0000  depth 1"
+        );
+        assert_eq!(
+            event.format(TraceFormat::Json),
+            r#"{"index":4,"source":null,"instruction":"SUB *0x0011","operand":1,"address":2,"changes":[{"cell":2,"old":5,"new":4},{"cell":65532,"old":4,"new":0}],"depth":1}"#
+        );
+        assert_eq!(json_string("a\"\\\n"), r#""a\"\\\u000a""#);
+    }
+}